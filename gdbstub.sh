@@ -0,0 +1,2 @@
+#!/bin/sh
+qemu-system-x86_64 -drive format=raw,file=target/x86_64-juner_os/debug/bootimage-juner_os.bin -serial stdio -serial tcp::1235,server,nowait
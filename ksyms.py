@@ -0,0 +1,114 @@
+#!/usr/bin/env python3
+"""Writes the function symbols of the kernel into its .ksyms section.
+
+The kernel reserves the section (see src/backtrace.rs) and uses the table to
+print function names in backtraces. Run it on the linked kernel before the
+boot image is built; ksyms.sh does that as the cargo runner.
+
+    python3 ksyms.py target/x86_64-juner_os/debug/juner_os
+"""
+
+import re
+import struct
+import subprocess
+import sys
+
+SECTION = b".ksyms"
+MAGIC = b"KSYM"
+ENTRY = struct.Struct("<QQII")
+HASH_SUFFIX = re.compile(r"::h[0-9a-f]{16}$")
+
+
+def find_section(elf, name):
+    """Returns the file offset and size of the section called name."""
+    if elf[:4] != b"\x7fELF" or elf[4] != 2:
+        sys.exit("not a 64-bit ELF file")
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+
+    def header(index):
+        # sh_name, sh_offset, sh_size
+        base = shoff + index * shentsize
+        return (struct.unpack_from("<I", elf, base)[0],
+                struct.unpack_from("<Q", elf, base + 0x18)[0],
+                struct.unpack_from("<Q", elf, base + 0x20)[0])
+
+    _, names, _ = header(shstrndx)
+    for index in range(shnum):
+        name_offset, offset, size = header(index)
+        start = names + name_offset
+        if elf[start:elf.index(b"\0", start)] == name:
+            return offset, size
+    sys.exit("no %s section, is the kernel built with backtrace support?" % name.decode())
+
+
+def read_symbols(path):
+    """Returns (address, size, name) of every function, sorted by address."""
+    for nm in ("nm", "llvm-nm"):
+        try:
+            output = subprocess.run(
+                [nm, "--defined-only", "--print-size", "--demangle", path],
+                check=True, stdout=subprocess.PIPE, universal_newlines=True).stdout
+            break
+        except (OSError, subprocess.CalledProcessError):
+            continue
+    else:
+        sys.exit("nm or llvm-nm is needed")
+
+    symbols = {}
+    for line in output.splitlines():
+        fields = line.split(None, 3)
+        # 有大小的是 "地址 大小 类型 名字", 汇编里的标号没有大小
+        if len(fields) == 4 and fields[2] in "tT":
+            address, size, name = int(fields[0], 16), int(fields[1], 16), fields[3]
+        elif len(fields) == 3 and fields[1] in "tT":
+            address, size, name = int(fields[0], 16), 0, fields[2]
+        else:
+            continue
+        name = HASH_SUFFIX.sub("", name)
+        if address not in symbols or symbols[address][0] < size:
+            symbols[address] = (size, name)
+
+    ordered = sorted(symbols.items())
+    result = []
+    for index, (address, (size, name)) in enumerate(ordered):
+        # 没有大小的符号算到下一个符号为止
+        if size == 0 and index + 1 < len(ordered):
+            size = ordered[index + 1][0] - address
+        result.append((address, size, name.encode()))
+    return result
+
+
+def build_table(symbols, capacity):
+    names_start = 8 + len(symbols) * ENTRY.size
+    entries = bytearray()
+    names = bytearray()
+    for address, size, name in symbols:
+        entries += ENTRY.pack(address, size, names_start + len(names), len(name))
+        names += name
+    table = MAGIC + struct.pack("<I", len(symbols)) + entries + names
+    if len(table) > capacity:
+        sys.exit("symbol table needs %d bytes but .ksyms has %d, raise KSYMS_SIZE"
+                 % (len(table), capacity))
+    return table
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: ksyms.py KERNEL")
+    path = sys.argv[1]
+    with open(path, "rb") as f:
+        elf = f.read()
+    offset, size = find_section(elf, SECTION)
+    if elf[offset:offset + 4] != MAGIC:
+        sys.exit(".ksyms does not start with the expected magic")
+    symbols = read_symbols(path)
+    table = build_table(symbols, size)
+    with open(path, "r+b") as f:
+        f.seek(offset)
+        f.write(table + bytes(size - len(table)))
+    print("ksyms: wrote %d symbols (%d of %d bytes)" % (len(symbols), len(table), size))
+
+
+if __name__ == "__main__":
+    main()
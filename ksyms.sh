@@ -0,0 +1,4 @@
+#!/bin/sh
+# cargo 的 runner: 先把符号表写进内核的 .ksyms 段, 再交给 bootimage 运行
+python3 "$(dirname "$0")/ksyms.py" "$1" || exit 1
+exec bootimage runner "$@"
@@ -0,0 +1,272 @@
+//! Minimal ACPI table discovery.
+//!
+//! Finds the RSDP in the BIOS areas, walks the RSDT or XSDT and parses the
+//! MADT, which describes the interrupt controllers and processors. The tables
+//! are read through the bootloader's mapping of physical memory.
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::{mem, ptr, slice};
+use log::*;
+use x86_64::PhysAddr;
+
+use crate::memory::phys_to_virt;
+
+/// A processor listed in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct Processor {
+    pub acpi_id: u8,
+    pub apic_id: u8,
+    /// The processor is enabled or can be brought online.
+    pub usable: bool,
+}
+
+/// An I/O APIC listed in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The first global system interrupt handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// A legacy ISA IRQ that is wired to a different global system interrupt or
+/// with a different polarity or trigger mode than the ISA default.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// Bits 0-1 are the polarity and bits 2-3 the trigger mode, as in the MADT.
+    pub flags: u16,
+}
+
+/// The interrupt controller configuration described by the MADT.
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// The machine also has 8259 PICs that must be disabled to use the APICs.
+    pub legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// Returns the global system interrupt of the legacy ISA `irq` and its
+    /// polarity/trigger flags.
+    pub fn isa_irq(&self, irq: u8) -> (u32, u16) {
+        self.overrides
+            .iter()
+            .find(|o| o.irq == irq)
+            .map(|o| (o.gsi, o.flags))
+            .unwrap_or((u32::from(irq), 0))
+    }
+}
+
+// 只读其中几个字段, 其余的保留下来对齐表的布局
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // 以下字段只有 ACPI 2.0 以后才有
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+const HEADER_SIZE: u64 = mem::size_of::<SdtHeader>() as u64;
+/// The local APIC address and flags that come before the MADT entries.
+const MADT_FIELDS_SIZE: u64 = 8;
+
+static MADT: OnceCell<Madt> = OnceCell::uninit();
+
+/// Looks up the ACPI tables. Must be called after the heap is initialized.
+pub fn init() {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => {
+            warn!("ACPI: no RSDP found");
+            return;
+        }
+    };
+    match find_table(&rsdp, b"APIC").and_then(|addr| unsafe { parse_madt(addr) }) {
+        Some(madt) => {
+            info!(
+                "ACPI: {} processors, {} I/O APICs, local APIC at {:#x}",
+                madt.processors.len(),
+                madt.io_apics.len(),
+                madt.local_apic_address.as_u64()
+            );
+            MADT.try_init_once(|| madt).ok();
+        }
+        None => warn!("ACPI: no valid MADT found"),
+    }
+}
+
+/// Returns the parsed MADT, if the firmware provides one.
+pub fn madt() -> Option<&'static Madt> {
+    MADT.try_get().ok()
+}
+
+fn find_rsdp() -> Option<Rsdp> {
+    // 先找 EBDA 的前 1 KiB, 再找 BIOS 只读区
+    let ebda = unsafe { ptr::read(phys_to_virt(PhysAddr::new(0x40e)).as_ptr::<u16>()) };
+    let ebda = u64::from(ebda) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+    for &(start, end) in areas.iter().filter(|(start, _)| *start != 0) {
+        for addr in (start..end).step_by(16) {
+            let bytes = unsafe { phys_slice(PhysAddr::new(addr), 20) };
+            if &bytes[..8] == b"RSD PTR " && checksum(bytes) {
+                let rsdp: Rsdp =
+                    unsafe { ptr::read_unaligned(phys_to_virt(PhysAddr::new(addr)).as_ptr()) };
+                return Some(rsdp);
+            }
+        }
+    }
+    None
+}
+
+fn find_table(rsdp: &Rsdp, signature: &[u8; 4]) -> Option<PhysAddr> {
+    // 2.0 以后用 64 位指针的 XSDT
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(u64::from(rsdp.rsdt_address)), 4)
+    };
+    let header = unsafe { read_header(root) };
+    // 长度比表头还短的表是坏的
+    let count = u64::from(header.length).checked_sub(HEADER_SIZE)? / entry_size;
+    let entries = root + HEADER_SIZE;
+    (0..count)
+        .map(|i| {
+            let entry = phys_to_virt(entries + i * entry_size);
+            let addr = if entry_size == 8 {
+                unsafe { ptr::read_unaligned(entry.as_ptr::<u64>()) }
+            } else {
+                u64::from(unsafe { ptr::read_unaligned(entry.as_ptr::<u32>()) })
+            };
+            PhysAddr::new(addr)
+        })
+        .find(|&table| {
+            let header = unsafe { read_header(table) };
+            &header.signature == signature
+                && u64::from(header.length) >= HEADER_SIZE
+                && checksum(unsafe { phys_slice(table, header.length as usize) })
+        })
+}
+
+unsafe fn parse_madt(addr: PhysAddr) -> Option<Madt> {
+    let header = read_header(addr);
+    let entries_size = u64::from(header.length).checked_sub(HEADER_SIZE + MADT_FIELDS_SIZE)?;
+    let base = addr + HEADER_SIZE;
+    let local_apic = ptr::read_unaligned(phys_to_virt(base).as_ptr::<u32>());
+    let flags = ptr::read_unaligned(phys_to_virt(base + 4u64).as_ptr::<u32>());
+
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(u64::from(local_apic)),
+        legacy_pics: flags & 1 != 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let entries = phys_slice(base + MADT_FIELDS_SIZE, entries_size as usize);
+    let mut offset = 0;
+    while offset + 2 <= entries.len() {
+        let kind = entries[offset];
+        let length = entries[offset + 1] as usize;
+        if length < 2 || offset + length > entries.len() {
+            break;
+        }
+        let entry = &entries[offset..offset + length];
+        offset += length;
+        if let Some(min) = madt_entry_length(kind) {
+            if length < min {
+                warn!(
+                    "ACPI: skipping MADT entry of type {} with length {} (need {})",
+                    kind, length, min
+                );
+                continue;
+            }
+        }
+        match kind {
+            0 => madt.processors.push(Processor {
+                acpi_id: entry[2],
+                apic_id: entry[3],
+                usable: read_u32(entry, 4) & 0b11 != 0,
+            }),
+            1 => madt.io_apics.push(IoApicInfo {
+                id: entry[2],
+                address: PhysAddr::new(u64::from(read_u32(entry, 4))),
+                gsi_base: read_u32(entry, 8),
+            }),
+            2 => madt.overrides.push(InterruptOverride {
+                irq: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: u16::from_le_bytes([entry[8], entry[9]]),
+            }),
+            // 64 位的本地 APIC 地址
+            5 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&entry[4..12]);
+                madt.local_apic_address = PhysAddr::new(u64::from_le_bytes(bytes));
+            }
+            _ => {}
+        }
+    }
+    Some(madt)
+}
+
+/// Returns the smallest valid length of the MADT entries that are parsed.
+fn madt_entry_length(kind: u8) -> Option<usize> {
+    match kind {
+        // 本地 APIC
+        0 => Some(8),
+        // I/O APIC
+        1 => Some(12),
+        // 中断重定向
+        2 => Some(10),
+        // 64 位的本地 APIC 地址
+        5 => Some(12),
+        _ => None,
+    }
+}
+
+unsafe fn read_header(addr: PhysAddr) -> SdtHeader {
+    ptr::read_unaligned(phys_to_virt(addr).as_ptr())
+}
+
+unsafe fn phys_slice(addr: PhysAddr, len: usize) -> &'static [u8] {
+    slice::from_raw_parts(phys_to_virt(addr).as_ptr(), len)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(word)
+}
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
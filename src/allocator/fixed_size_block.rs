@@ -0,0 +1,201 @@
+use super::{check_lock_order, GrowableHeap, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{
+    mem,
+    ptr::{self, NonNull},
+};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// The block sizes to use.
+///
+/// Most kernel allocations come from the mal interpreter: `Rc<Vec<MalVal>>`
+/// boxes are 40 bytes and a `MalVal` is 48 bytes, so there is a class for
+/// them between 32 and 64.
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 48, 64, 128, 256, 512, 1024, 2048];
+
+/// The alignment of each block size, the largest power of two dividing it.
+const BLOCK_ALIGNS: &[usize] = &[8, 16, 32, 16, 64, 128, 256, 512, 1024, 2048];
+
+pub const SIZE_CLASS_COUNT: usize = BLOCK_SIZES.len();
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Hit/miss counters of a single size class.
+///
+/// A hit is an allocation served from the class's free list, a miss is one that
+/// had to carve a fresh block out of the fallback heap.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Usage counters of the whole heap, in bytes of requested layouts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UsageStats {
+    pub used: usize,
+    pub peak: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+}
+
+/// A slab allocator with one free list per size class that falls back to the
+/// growable linked list heap for large allocations and empty free lists.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; SIZE_CLASS_COUNT],
+    stats: [SizeClassStats; SIZE_CLASS_COUNT],
+    large_allocations: u64,
+    usage: UsageStats,
+    fallback_allocator: GrowableHeap,
+}
+
+impl FixedSizeBlockAllocator {
+    /// Creates an empty FixedSizeBlockAllocator.
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        const NO_STATS: SizeClassStats = SizeClassStats {
+            block_size: 0,
+            hits: 0,
+            misses: 0,
+        };
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; SIZE_CLASS_COUNT],
+            stats: [NO_STATS; SIZE_CLASS_COUNT],
+            large_allocations: 0,
+            usage: UsageStats {
+                used: 0,
+                peak: 0,
+                allocations: 0,
+                deallocations: 0,
+            },
+            fallback_allocator: GrowableHeap::empty(),
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        for (stats, &block_size) in self.stats.iter_mut().zip(BLOCK_SIZES) {
+            stats.block_size = block_size;
+        }
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// Returns the hit/miss counters of every size class.
+    pub fn size_class_stats(&self) -> [SizeClassStats; SIZE_CLASS_COUNT] {
+        self.stats
+    }
+
+    /// Returns the number of allocations that were too large for any size class.
+    pub fn large_allocations(&self) -> u64 {
+        self.large_allocations
+    }
+
+    /// Returns the number of bytes handed out, the peak of that and the number of
+    /// allocations and deallocations so far.
+    pub fn usage(&self) -> UsageStats {
+        self.usage
+    }
+
+    /// Returns the heap that backs the size classes.
+    pub fn fallback(&self) -> &GrowableHeap {
+        &self.fallback_allocator
+    }
+
+    /// Allocates using the fallback allocator.
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self.fallback_allocator.allocate(layout) {
+            Some(ptr) => ptr.as_ptr(),
+            None => ptr::null_mut(),
+        }
+    }
+
+    fn record_alloc(&mut self, size: usize) {
+        self.usage.used += size;
+        self.usage.allocations += 1;
+        if self.usage.used > self.usage.peak {
+            self.usage.peak = self.usage.used;
+        }
+    }
+
+    fn record_dealloc(&mut self, size: usize) {
+        self.usage.used -= size;
+        self.usage.deallocations += 1;
+    }
+}
+
+/// Choose an appropriate block size for the given layout.
+///
+/// Returns an index into the `BLOCK_SIZES` array.
+fn list_index(layout: &Layout) -> Option<usize> {
+    BLOCK_SIZES
+        .iter()
+        .zip(BLOCK_ALIGNS)
+        .position(|(&size, &align)| size >= layout.size() && align >= layout.align())
+}
+
+// 中断处理程序也会分配内存, 拿着锁的时候不能被中断
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        check_lock_order();
+        without_interrupts(|| {
+            let mut allocator = self.lock();
+            let ptr = match list_index(&layout) {
+                Some(index) => match allocator.list_heads[index].take() {
+                    Some(node) => {
+                        allocator.list_heads[index] = node.next.take();
+                        allocator.stats[index].hits += 1;
+                        node as *mut ListNode as *mut u8
+                    }
+                    None => {
+                        // no block exists in list => allocate new block
+                        allocator.stats[index].misses += 1;
+                        let block_size = BLOCK_SIZES[index];
+                        let block_align = BLOCK_ALIGNS[index];
+                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                        allocator.fallback_alloc(layout)
+                    }
+                },
+                None => {
+                    allocator.large_allocations += 1;
+                    allocator.fallback_alloc(layout)
+                }
+            };
+            if !ptr.is_null() {
+                allocator.record_alloc(layout.size());
+            }
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        check_lock_order();
+        without_interrupts(|| {
+            let mut allocator = self.lock();
+            allocator.record_dealloc(layout.size());
+            match list_index(&layout) {
+                Some(index) => {
+                    let new_node = ListNode {
+                        next: allocator.list_heads[index].take(),
+                    };
+                    // verify that block has size and alignment required for storing node
+                    assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                    assert!(mem::align_of::<ListNode>() <= BLOCK_ALIGNS[index]);
+                    let new_node_ptr = ptr as *mut ListNode;
+                    new_node_ptr.write(new_node);
+                    allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                }
+                None => {
+                    let ptr = NonNull::new(ptr).unwrap();
+                    allocator.fallback_allocator.deallocate(ptr, layout);
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,209 @@
+use alloc::alloc::Layout;
+use core::ptr::NonNull;
+use linked_list_allocator::Heap;
+
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+use crate::memory::{
+    self, paging,
+    vmm::{self, Backing, VmError},
+};
+use crate::sync::{Mutex, MutexGuard};
+
+pub mod fixed_size_block;
+
+use fixed_size_block::{SizeClassStats, SIZE_CLASS_COUNT};
+
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB 初始大小
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB 堆增长的上限
+pub const HEAP_GROW_STEP: usize = 64 * 1024; // 每次至少增长 64 KiB
+pub const HEAP_HUGE_PAGES: bool = true; // 大块增长时使用 2 MiB 大页
+
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// A wrapper around `sync::Mutex` to permit trait implementations.
+pub struct Locked<A> {
+    inner: Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+/// A linked list heap that maps additional pages at its top when it runs out
+/// of memory, up to `HEAP_MAX_SIZE` bytes.
+pub struct GrowableHeap {
+    heap: Heap,
+}
+
+impl GrowableHeap {
+    pub const fn empty() -> Self {
+        GrowableHeap {
+            heap: Heap::empty(),
+        }
+    }
+
+    /// Initializes the heap with an already mapped memory region.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// memory range is mapped and unused, and that the pages above it up to
+    /// `HEAP_MAX_SIZE` are free to be mapped by the heap. It must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap.init(heap_start, heap_size);
+    }
+
+    /// Returns the number of bytes currently mapped for the heap.
+    pub fn size(&self) -> usize {
+        self.heap.size()
+    }
+
+    /// Allocates a block for `layout`, growing the heap if the free list cannot
+    /// satisfy the request.
+    pub fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        if let Ok(ptr) = self.heap.allocate_first_fit(layout) {
+            return Some(ptr);
+        }
+        // 空间不够的时候扩展堆, 多留出对齐需要的空间
+        if self.grow(layout.size() + layout.align()) {
+            self.heap.allocate_first_fit(layout).ok()
+        } else {
+            None
+        }
+    }
+
+    /// Returns a block to the free list.
+    ///
+    /// This function is unsafe because the caller must guarantee that `ptr` was
+    /// returned by `allocate` with the same `layout`.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        self.heap.deallocate(ptr, layout)
+    }
+
+    /// Maps at least `min_bytes` of new pages at the top of the heap.
+    ///
+    /// Returns `false` if the heap is already at its ceiling or no frame could be
+    /// mapped at all.
+    fn grow(&mut self, min_bytes: usize) -> bool {
+        let size = self.heap.size();
+        let room = HEAP_MAX_SIZE - size;
+        let top = self.heap.top();
+        let mut bytes = align_up(min_bytes.max(HEAP_GROW_STEP), 4096);
+        if HEAP_HUGE_PAGES && bytes >= HUGE_PAGE_SIZE {
+            // 增长到 2 MiB 边界, 这样中间的部分都可以用大页映射
+            bytes = align_up(top + bytes, HUGE_PAGE_SIZE) - top;
+        }
+        let bytes = bytes.min(room);
+        if bytes == 0 {
+            return false;
+        }
+
+        let mapped = map_heap_pages(top, bytes, HEAP_HUGE_PAGES);
+        if mapped == 0 {
+            return false;
+        }
+        unsafe { self.heap.extend(mapped) };
+        true
+    }
+}
+
+/// Panics if the processor this runs on holds `memory::MAPPER` or
+/// `memory::FRAME_ALLOCATOR`.
+///
+/// The heap takes those locks while it holds its own to map new pages, so the
+/// lock order is heap, mapper, frame allocator. Allocating or freeing while
+/// holding one of the later locks could deadlock against a processor that is
+/// growing the heap, or against this one.
+fn check_lock_order() {
+    assert!(
+        !memory::MAPPER.is_held_here() && !memory::FRAME_ALLOCATOR.is_held_here(),
+        "heap used while holding the mapper or frame allocator lock"
+    );
+}
+
+/// Reserves virtual space for the heap up to its ceiling, maps the initial
+/// heap region and hands it to the global allocator.
+///
+/// Must be called after `memory::install`.
+pub fn init_heap() -> Result<(), VmError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    // 按大页对齐, 这样堆增长时可以使用 2 MiB 的页
+    let heap_start = vmm::reserve(
+        "kernel heap",
+        HEAP_MAX_SIZE as u64,
+        HUGE_PAGE_SIZE as u64,
+        flags,
+        Backing::Manual,
+    )?
+    .as_u64() as usize;
+    let mapped = map_heap_pages(heap_start, HEAP_SIZE, false);
+    if mapped < HEAP_SIZE {
+        return Err(VmError::OutOfMemory);
+    }
+
+    unsafe {
+        super::ALLOCATOR.lock().init(heap_start, HEAP_SIZE);
+    }
+
+    Ok(())
+}
+
+/// Maps `size` bytes of fresh frames starting at `start` and returns how many
+/// bytes were mapped before the first failure.
+fn map_heap_pages(start: usize, size: usize, huge: bool) -> usize {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    paging::map_range(VirtAddr::new(start as u64), size as u64, flags, huge) as usize
+}
+
+/// A snapshot of the kernel heap usage.
+///
+/// `free` counts every mapped byte that is not handed out, including blocks
+/// cached in the size class free lists.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: usize,
+    pub max_size: usize,
+    pub used: usize,
+    pub free: usize,
+    pub peak: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+}
+
+/// Returns the current usage of the kernel heap.
+pub fn heap_stats() -> HeapStats {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let allocator = super::ALLOCATOR.lock();
+        let usage = allocator.usage();
+        let size = allocator.fallback().size();
+        HeapStats {
+            size,
+            max_size: HEAP_MAX_SIZE,
+            used: usage.used,
+            free: size.saturating_sub(usage.used),
+            peak: usage.peak,
+            allocations: usage.allocations,
+            deallocations: usage.deallocations,
+        }
+    })
+}
+
+/// Returns the hit/miss counters of every size class of the global allocator
+/// together with the number of allocations that bypassed the size classes.
+pub fn size_class_stats() -> ([SizeClassStats; SIZE_CLASS_COUNT], u64) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let allocator = super::ALLOCATOR.lock();
+        (allocator.size_class_stats(), allocator.large_allocations())
+    })
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,193 @@
+//! Symbolized stack backtraces.
+//!
+//! The kernel is built with frame pointers (see `.cargo/config`), so every
+//! frame starts with the caller's `rbp` followed by the return address. The
+//! function names come from the `.ksyms` section, which `ksyms.py` fills in
+//! after linking; without it only the addresses are printed.
+
+use core::ptr;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+use crate::memory::address_space;
+use crate::{println, serial_println};
+
+/// Size of the `.ksyms` section. `ksyms.py` refuses tables that do not fit.
+const KSYMS_SIZE: usize = 512 * 1024;
+const ENTRY_SIZE: usize = 24;
+const MAX_FRAMES: usize = 32;
+
+/// The symbol table, in the layout `ksyms.py` writes: the magic, the number
+/// of symbols and then one entry per function sorted by address (start `u64`,
+/// size `u64`, name offset `u32` from the start of the table, name length
+/// `u32`), followed by the names.
+#[repr(C)]
+struct SymbolTable {
+    magic: [u8; 4],
+    count: u32,
+    data: [u8; KSYMS_SIZE - 8],
+}
+
+// 链接之后由脚本改写, 所以不能让编译器当成常量
+#[used]
+#[link_section = ".ksyms"]
+static mut KSYMS: SymbolTable = SymbolTable {
+    magic: *b"KSYM",
+    count: 0,
+    data: [0; KSYMS_SIZE - 8],
+};
+
+/// Prints to the screen and the serial port.
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        println!($($arg)*);
+        serial_println!($($arg)*);
+    }};
+}
+
+/// Returns the name of the function containing `addr` and the offset of
+/// `addr` in it.
+pub fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    let table = unsafe { &KSYMS };
+    let count = unsafe { ptr::read_volatile(&table.count) } as usize;
+    let count = count.min(table.data.len() / ENTRY_SIZE);
+    let entry = |index: usize| {
+        let bytes = &table.data[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE];
+        let start = read_u64(&bytes[0..8]);
+        let size = read_u64(&bytes[8..16]);
+        let name_offset = read_u32(&bytes[16..20]) as usize;
+        let name_len = read_u32(&bytes[20..24]) as usize;
+        (start, size, name_offset, name_len)
+    };
+
+    // 找最后一个起始地址不大于 addr 的函数
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if entry(middle).0 <= addr {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let (start, size, name_offset, name_len) = entry(low.checked_sub(1)?);
+    if addr - start >= size {
+        return None;
+    }
+    // 名字的偏移是相对于整个表的
+    let name = table
+        .data
+        .get(name_offset.checked_sub(8)?..name_offset - 8 + name_len)?;
+    let name = core::str::from_utf8(name).ok()?;
+    Some((name, addr - start))
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut word = [0; 8];
+    word.copy_from_slice(bytes);
+    u64::from_le_bytes(word)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(bytes);
+    u32::from_le_bytes(word)
+}
+
+fn print_header() {
+    if unsafe { ptr::read_volatile(&KSYMS.count) } == 0 {
+        report!("backtrace (no symbols, run ksyms.py on the kernel):");
+    } else {
+        report!("backtrace:");
+    }
+}
+
+/// Prints the backtrace of the caller.
+#[inline(never)]
+pub fn print() {
+    print_header();
+    walk(current_rbp(), 0);
+}
+
+/// Prints the backtrace of the code that was interrupted by the exception
+/// with `stack_frame`, starting with the faulting instruction.
+#[inline(never)]
+pub fn print_fault(stack_frame: &InterruptStackFrame) {
+    let rip = stack_frame.instruction_pointer.as_u64();
+    print_header();
+    print_frame(0, rip, rip);
+    // 中断处理函数的帧后面紧跟着 CPU 压入的栈帧 (有错误码时中间隔一个字)
+    let target = stack_frame as *const InterruptStackFrame as u64;
+    let mut handler_frame = None;
+    frames(current_rbp(), |rbp, _| {
+        if rbp + 8 == target || rbp + 16 == target {
+            handler_frame = Some(rbp);
+            false
+        } else {
+            true
+        }
+    });
+    match handler_frame {
+        Some(rbp) => walk(unsafe { ptr::read(rbp as *const u64) }, 1),
+        None => report!("  (interrupted frames not found)"),
+    }
+}
+
+fn walk(rbp: u64, first: usize) {
+    let mut index = first;
+    frames(rbp, |_, return_address| {
+        // 返回地址可能已经是下一个函数了, 用 call 指令所在的地址查
+        print_frame(index, return_address, return_address - 1);
+        index += 1;
+        true
+    });
+}
+
+fn print_frame(index: usize, addr: u64, lookup: u64) {
+    match symbolize(lookup) {
+        Some((name, offset)) => report!(
+            "  {:2}: {:#018x} {}+{:#x}",
+            index,
+            addr,
+            name,
+            offset + (addr - lookup)
+        ),
+        None => report!("  {:2}: {:#018x} <unknown>", index, addr),
+    }
+}
+
+/// Calls `f` with the frame pointer and return address of every frame,
+/// starting at `rbp`, until it returns `false` or the chain ends.
+fn frames(mut rbp: u64, mut f: impl FnMut(u64, u64) -> bool) {
+    for _ in 0..MAX_FRAMES {
+        if rbp % 8 != 0 || !is_mapped(rbp) || !is_mapped(rbp + 8) {
+            return;
+        }
+        let (next, return_address) = unsafe {
+            let frame = rbp as *const u64;
+            (ptr::read(frame), ptr::read(frame.add(1)))
+        };
+        if return_address == 0 || !f(rbp, return_address) {
+            return;
+        }
+        // 栈向下增长, 调用者的帧在更高的地址
+        if next <= rbp {
+            return;
+        }
+        rbp = next;
+    }
+}
+
+fn is_mapped(addr: u64) -> bool {
+    match VirtAddr::try_new(addr) {
+        Ok(addr) => address_space::translate_active(addr).is_some(),
+        Err(_) => false,
+    }
+}
+
+#[inline(always)]
+fn current_rbp() -> u64 {
+    let rbp: u64;
+    unsafe { llvm_asm!("mov %rbp, $0" : "=r"(rbp)) };
+    rbp
+}
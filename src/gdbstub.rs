@@ -0,0 +1,468 @@
+//! A GDB remote serial protocol stub on COM2.
+//!
+//! Once enabled, every breakpoint exception and every finished single step
+//! stops the processor that hit it and hands control to GDB over the second
+//! serial port. The stub supports reading and writing registers and memory,
+//! continuing, single stepping through the trap flag and software breakpoints
+//! (`Z0`/`z0`). Connect with `target remote` on the host end of COM2, for
+//! example `-serial tcp::1235,server,nowait` in QEMU.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use uart_16550::SerialPort;
+use x86_64::VirtAddr;
+
+use crate::interrupts::trap::TrapFrame;
+use crate::memory::{address_space, phys_to_virt};
+use crate::serial::{receive_from, COM2};
+use crate::sync::Mutex;
+
+/// The largest packet the stub accepts, announced to GDB.
+const PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 32;
+
+const TRAP_FLAG: u64 = 1 << 8;
+const INT3: u8 = 0xcc;
+// 停下来的原因都报告成 SIGTRAP
+const STOP_REPLY: &[u8] = b"S05";
+
+lazy_static! {
+    static ref PORT: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM2) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    saved: u8,
+}
+
+struct Session {
+    /// GDB talked to the stub since it was enabled or last detached.
+    attached: bool,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    reply: Reply,
+}
+
+static SESSION: Mutex<Session> = Mutex::new(Session {
+    attached: false,
+    breakpoints: [None; MAX_BREAKPOINTS],
+    reply: Reply {
+        buf: [0; PACKET_SIZE],
+        len: 0,
+    },
+});
+
+/// Makes breakpoints and single steps stop in the stub.
+pub fn enable() {
+    lazy_static::initialize(&PORT);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Returns whether the stub handles breakpoints.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Enables the stub and stops here until GDB continues.
+pub fn breakpoint() {
+    enable();
+    x86_64::instructions::interrupts::int3();
+}
+
+/// Talks to GDB until it resumes the interrupted code. Called by the
+/// breakpoint and debug exception handlers; returns `false` if the stub is
+/// disabled or another processor is already stopped in it.
+pub(crate) fn handle_trap(frame: &mut TrapFrame) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    let mut session = match SESSION.try_lock() {
+        Some(session) => session,
+        None => return false,
+    };
+    frame.rflags &= !TRAP_FLAG;
+    // 还没连上的 GDB 会先问停止的原因
+    if session.attached {
+        send_packet(STOP_REPLY);
+    }
+    session.serve(frame);
+    true
+}
+
+impl Session {
+    fn serve(&mut self, frame: &mut TrapFrame) {
+        let mut packet = [0; PACKET_SIZE];
+        loop {
+            let len = receive_packet(&mut packet);
+            self.attached = true;
+            self.reply.clear();
+            let (command, args) = match packet[..len].split_first() {
+                Some((&command, args)) => (command, args),
+                None => (0, &[][..]),
+            };
+            match command {
+                b'?' => self.reply.push_str(STOP_REPLY),
+                b'g' => self.read_registers(frame),
+                b'G' => self.write_registers(frame, args),
+                b'm' => self.read_memory(args),
+                b'M' => self.write_memory(args),
+                b'c' | b's' => {
+                    if let Some(addr) = parse_hex(args) {
+                        frame.rip = addr;
+                    }
+                    if command == b's' {
+                        frame.rflags |= TRAP_FLAG;
+                    }
+                    return;
+                }
+                b'Z' | b'z' => self.breakpoint_packet(command == b'Z', args),
+                b'D' | b'k' => {
+                    self.remove_breakpoints();
+                    self.attached = false;
+                    if command == b'D' {
+                        send_packet(b"OK");
+                    }
+                    return;
+                }
+                b'H' => self.reply.push_str(b"OK"),
+                b'q' if args.starts_with(b"Supported") => {
+                    self.reply.push_str(b"PacketSize=");
+                    self.reply.push_hex(PACKET_SIZE as u64);
+                }
+                b'q' if args == b"Attached" => self.reply.push_str(b"1"),
+                // 其余的包回复空包, 表示不支持
+                _ => {}
+            }
+            send_packet(self.reply.as_bytes());
+        }
+    }
+
+    /// Replies with the registers in the order of GDB's amd64 description.
+    fn read_registers(&mut self, frame: &TrapFrame) {
+        for &value in registers(frame).iter() {
+            self.reply.push_le(value, 8);
+        }
+        self.reply.push_le(frame.rflags, 4);
+        self.reply.push_le(frame.cs, 4);
+        self.reply.push_le(frame.ss, 4);
+        // ds, es, fs, gs
+        for _ in 0..4 {
+            self.reply.push_le(0, 4);
+        }
+    }
+
+    fn write_registers(&mut self, frame: &mut TrapFrame, args: &[u8]) {
+        let mut values = [0; 18];
+        for (i, value) in values.iter_mut().enumerate() {
+            let width = if i < 17 { 8 } else { 4 };
+            let start = if i < 17 { i * 16 } else { 17 * 16 };
+            match args.get(start..start + width * 2).and_then(parse_le) {
+                Some(parsed) => *value = parsed,
+                None => return self.reply.push_str(b"E01"),
+            }
+        }
+        for (target, value) in registers_mut(frame).iter_mut().zip(values.iter()) {
+            **target = *value;
+        }
+        self.reply.push_str(b"OK");
+    }
+
+    fn read_memory(&mut self, args: &[u8]) {
+        let (addr, len) = match parse_range(args) {
+            Some(range) => range,
+            None => return self.reply.push_str(b"E01"),
+        };
+        let len = len.min((PACKET_SIZE / 2) as u64);
+        for offset in 0..len {
+            match read_byte(addr.wrapping_add(offset)) {
+                Some(byte) => self.reply.push_le(u64::from(byte), 1),
+                None if offset == 0 => return self.reply.push_str(b"E14"),
+                // 读到一部分就返回读到的
+                None => return,
+            }
+        }
+    }
+
+    fn write_memory(&mut self, args: &[u8]) {
+        let colon = args.iter().position(|&b| b == b':');
+        let (range, data) = match colon {
+            Some(colon) => (&args[..colon], &args[colon + 1..]),
+            None => return self.reply.push_str(b"E01"),
+        };
+        let (addr, len) = match parse_range(range) {
+            Some(range) if range.1 as usize * 2 == data.len() => range,
+            _ => return self.reply.push_str(b"E01"),
+        };
+        for offset in 0..len as usize {
+            let byte = match parse_hex(&data[offset * 2..offset * 2 + 2]) {
+                Some(byte) => byte as u8,
+                None => return self.reply.push_str(b"E01"),
+            };
+            let addr = addr.wrapping_add(offset as u64);
+            // 断点处读到的应该是原来的字节
+            let slot = self
+                .breakpoints
+                .iter_mut()
+                .flatten()
+                .find(|b| b.addr == addr);
+            let written = match slot {
+                Some(breakpoint) => {
+                    breakpoint.saved = byte;
+                    true
+                }
+                None => write_byte(addr, byte),
+            };
+            if !written {
+                return self.reply.push_str(b"E14");
+            }
+        }
+        self.reply.push_str(b"OK");
+    }
+
+    /// Handles `Z0,addr,kind` and `z0,addr,kind`. Other breakpoint types
+    /// are not supported.
+    fn breakpoint_packet(&mut self, insert: bool, args: &[u8]) {
+        if !args.starts_with(b"0,") {
+            return;
+        }
+        let addr = match parse_range(&args[2..]) {
+            Some((addr, _)) => addr,
+            None => return self.reply.push_str(b"E01"),
+        };
+        let slot = self
+            .breakpoints
+            .iter()
+            .position(|b| b.map_or(false, |b| b.addr == addr));
+        let ok = if insert {
+            slot.is_some() || self.insert_breakpoint(addr)
+        } else {
+            slot.map_or(true, |index| self.remove_breakpoint(index))
+        };
+        self.reply
+            .push_str(if ok { b"OK" as &[u8] } else { b"E14" });
+    }
+
+    fn insert_breakpoint(&mut self, addr: u64) -> bool {
+        let slot = match self.breakpoints.iter_mut().find(|b| b.is_none()) {
+            Some(slot) => slot,
+            None => return false,
+        };
+        match read_byte(addr) {
+            Some(saved) if write_byte(addr, INT3) => {
+                *slot = Some(Breakpoint { addr, saved });
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn remove_breakpoint(&mut self, index: usize) -> bool {
+        match self.breakpoints[index].take() {
+            Some(breakpoint) => write_byte(breakpoint.addr, breakpoint.saved),
+            None => true,
+        }
+    }
+
+    fn remove_breakpoints(&mut self) {
+        for index in 0..MAX_BREAKPOINTS {
+            self.remove_breakpoint(index);
+        }
+    }
+}
+
+/// The registers that `G` writes: the 64-bit ones and `eflags`.
+fn registers_mut(frame: &mut TrapFrame) -> [&mut u64; 18] {
+    [
+        &mut frame.rax,
+        &mut frame.rbx,
+        &mut frame.rcx,
+        &mut frame.rdx,
+        &mut frame.rsi,
+        &mut frame.rdi,
+        &mut frame.rbp,
+        &mut frame.rsp,
+        &mut frame.r8,
+        &mut frame.r9,
+        &mut frame.r10,
+        &mut frame.r11,
+        &mut frame.r12,
+        &mut frame.r13,
+        &mut frame.r14,
+        &mut frame.r15,
+        &mut frame.rip,
+        &mut frame.rflags,
+    ]
+}
+
+fn registers(frame: &TrapFrame) -> [u64; 17] {
+    [
+        frame.rax, frame.rbx, frame.rcx, frame.rdx, frame.rsi, frame.rdi, frame.rbp, frame.rsp,
+        frame.r8, frame.r9, frame.r10, frame.r11, frame.r12, frame.r13, frame.r14, frame.r15,
+        frame.rip,
+    ]
+}
+
+/// Returns the virtual address through which the byte at `addr` can be
+/// written even if its page is read-only, such as kernel code.
+fn writable_alias(addr: u64) -> Option<*mut u8> {
+    if addr >= 1 << 47 && addr < 0xffff_8000_0000_0000 {
+        return None;
+    }
+    let (phys, _) = address_space::translate_active(VirtAddr::new(addr))?;
+    Some(phys_to_virt(phys).as_mut_ptr())
+}
+
+fn read_byte(addr: u64) -> Option<u8> {
+    writable_alias(addr).map(|ptr| unsafe { ptr.read_volatile() })
+}
+
+fn write_byte(addr: u64, byte: u8) -> bool {
+    match writable_alias(addr) {
+        Some(ptr) => {
+            unsafe { ptr.write_volatile(byte) };
+            true
+        }
+        None => false,
+    }
+}
+
+/// A reply being built in place; the heap may be locked by the stopped code.
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.len < self.buf.len() {
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &[u8]) {
+        for &byte in s {
+            self.push(byte);
+        }
+    }
+
+    /// Appends `bytes` bytes of `value` in target (little endian) order.
+    fn push_le(&mut self, value: u64, bytes: usize) {
+        for i in 0..bytes {
+            let byte = (value >> (8 * i)) as u8;
+            self.push(HEX[usize::from(byte >> 4)]);
+            self.push(HEX[usize::from(byte & 0xf)]);
+        }
+    }
+
+    /// Appends `value` as a big endian hex number without leading zeros.
+    fn push_hex(&mut self, value: u64) {
+        let digits = (64 - value.leading_zeros() as usize + 3) / 4;
+        for i in (0..digits.max(1)).rev() {
+            self.push(HEX[((value >> (4 * i)) & 0xf) as usize]);
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parses a big endian hex number.
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter().try_fold(0u64, |value, &c| {
+        Some(value << 4 | u64::from(hex_digit(c)?))
+    })
+}
+
+/// Parses hex bytes in target (little endian) order.
+fn parse_le(s: &[u8]) -> Option<u64> {
+    s.chunks(2)
+        .rev()
+        .try_fold(0u64, |value, pair| Some(value << 8 | parse_hex(pair)?))
+}
+
+/// Parses `addr,len`.
+fn parse_range(s: &[u8]) -> Option<(u64, u64)> {
+    let comma = s.iter().position(|&b| b == b',')?;
+    Some((parse_hex(&s[..comma])?, parse_hex(&s[comma + 1..])?))
+}
+
+fn get_byte() -> u8 {
+    loop {
+        if let Some(byte) = receive_from(COM2) {
+            return byte;
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+fn put_byte(byte: u8) {
+    PORT.lock().send(byte);
+}
+
+/// Waits for a packet with a valid checksum, acknowledges it and stores its
+/// data in `buf`. Returns the length of the data.
+fn receive_packet(buf: &mut [u8; PACKET_SIZE]) -> usize {
+    loop {
+        while get_byte() != b'$' {}
+        let mut len = 0;
+        let mut sum = 0u8;
+        let mut byte = get_byte();
+        while byte != b'#' {
+            if len < buf.len() {
+                buf[len] = byte;
+                len += 1;
+            }
+            sum = sum.wrapping_add(byte);
+            byte = get_byte();
+        }
+        let checksum = parse_hex(&[get_byte(), get_byte()]);
+        if checksum == Some(u64::from(sum)) && len < buf.len() {
+            put_byte(b'+');
+            return len;
+        }
+        put_byte(b'-');
+    }
+}
+
+/// Sends a packet and waits until GDB acknowledges it.
+fn send_packet(data: &[u8]) {
+    let sum = data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    loop {
+        put_byte(b'$');
+        for &byte in data {
+            put_byte(byte);
+        }
+        put_byte(b'#');
+        put_byte(HEX[usize::from(sum >> 4)]);
+        put_byte(HEX[usize::from(sum & 0xf)]);
+        if get_byte() == b'+' {
+            return;
+        }
+    }
+}
@@ -5,11 +5,27 @@ use x86_64::structures::gdt::SegmentSelector;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+/// The double fault stack, on which stack overflows are reported.
+const DOUBLE_FAULT_STACK_PAGES: u64 = 5;
+
+/// Kernel stacks for interrupts and exceptions that arrive in ring 3.
+const PRIVILEGE_STACK_PAGES: u64 = 5;
+
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
+        // 栈溢出时缺页处理压不了栈, 会变成 double fault, 所以它用单独的栈
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
+            const STACK_SIZE: usize = 4096 * DOUBLE_FAULT_STACK_PAGES as usize;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            let stack_end = stack_start + STACK_SIZE;
+            stack_end
+        };
+        // 从用户态进入内核时切换到这个栈
+        tss.privilege_stack_table[0] = {
+            const STACK_SIZE: usize = 4096 * PRIVILEGE_STACK_PAGES as usize;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
@@ -23,27 +39,67 @@ lazy_static! {
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable,Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (gdt,Selectors{code_selector,tss_selector})
-    };
+    static ref GDT: (GlobalDescriptorTable,Selectors) = build_gdt(&TSS);
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+// syscall/sysret 要求段的顺序是: 内核代码, 内核数据, 用户数据, 用户代码
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::UserSegment(KERNEL_DATA_SEGMENT));
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (gdt,Selectors{code_selector,data_selector,user_data_selector,user_code_selector,tss_selector})
+}
+
+/// Present, writable data segment for ring 0.
+const KERNEL_DATA_SEGMENT: u64 = 0x00cf_9200_0000_ffff;
 
-pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+/// Returns the segment selectors. They are the same on every processor.
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+unsafe fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation::{load_ss, set_cs};
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
-    unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
-    }
+    gdt.0.load();
+    set_cs(gdt.1.code_selector);
+    load_ss(gdt.1.data_selector);
+    load_tss(gdt.1.tss_selector);
+}
+
+pub fn init() {
+    unsafe { load(&GDT) };
+}
+
+/// Gives the calling processor its own GDT and TSS, with interrupt stacks
+/// from the kernel region allocator that have guard pages. The bootstrap
+/// processor switches to them once the heap is up, the application processors
+/// when they start.
+pub fn init_percpu() {
+    use crate::memory::vmm;
+    use alloc::boxed::Box;
+
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        vmm::allocate_stack("double fault stack", DOUBLE_FAULT_STACK_PAGES)
+            .expect("no double fault stack");
+    tss.privilege_stack_table[0] = vmm::allocate_stack("privilege stack", PRIVILEGE_STACK_PAGES)
+        .expect("no privilege stack");
+    // 每个处理器一份, 一直用到关机
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+    let gdt: &'static (GlobalDescriptorTable, Selectors) = Box::leak(Box::new(build_gdt(tss)));
+    // 换表的时候不能来中断
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe { load(gdt) });
 }
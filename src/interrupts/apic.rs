@@ -0,0 +1,262 @@
+//! Local APIC and I/O APIC driver.
+//!
+//! When the MADT lists an I/O APIC, the 8259 PICs are masked and the legacy
+//! IRQs are routed through the I/O APIC to the local APIC of the bootstrap
+//! processor. Otherwise the kernel keeps using the PICs.
+
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use log::*;
+use x86_64::registers::model_specific::Msr;
+use x86_64::VirtAddr;
+
+use crate::acpi::{self, Madt};
+use crate::memory::mmio::map_mmio;
+use crate::sync::Mutex;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// 本地 APIC 寄存器的偏移
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TPR: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SVR: u64 = 0xf0;
+const LAPIC_SVR_ENABLE: u32 = 1 << 8;
+const LAPIC_ICR_LOW: u64 = 0x300;
+const LAPIC_ICR_HIGH: u64 = 0x310;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+
+// I/O APIC 通过选择寄存器和数据窗口间接访问
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+const MAX_IO_APICS: usize = 4;
+
+/// The vector of the spurious interrupt of the local APIC.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Virtual address of the local APIC registers, zero while the PICs are used.
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy)]
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        ptr::write_volatile(self.base.as_mut_ptr::<u32>(), register);
+        ptr::read_volatile((self.base + 0x10u64).as_ptr::<u32>())
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        ptr::write_volatile(self.base.as_mut_ptr::<u32>(), register);
+        ptr::write_volatile((self.base + 0x10u64).as_mut_ptr::<u32>(), value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.entries
+    }
+
+    fn redirection_register(&self, gsi: u32) -> u32 {
+        IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base)
+    }
+
+    unsafe fn set_redirection(&self, gsi: u32, low: u32, destination: u8) {
+        let register = self.redirection_register(gsi);
+        // 先写目标再写低位, 低位里带着屏蔽位
+        self.write(register + 1, u32::from(destination) << 24);
+        self.write(register, low);
+    }
+}
+
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]> = Mutex::new([None; MAX_IO_APICS]);
+
+/// Returns whether interrupts are delivered through the APICs.
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::Relaxed) != 0
+}
+
+fn cpu_has_apic() -> bool {
+    let features = unsafe { core::arch::x86_64::__cpuid(1) };
+    features.edx & (1 << 9) != 0
+}
+
+/// Switches interrupt delivery from the PICs to the APICs if the machine has
+/// them and routes the legacy IRQs in `irqs` to `vector_base + irq`, masked.
+///
+/// Returns `false` and leaves the PICs in charge otherwise. Must be called
+/// after the memory and ACPI initialization.
+pub fn init(vector_base: u8, irqs: &[u8]) -> bool {
+    let madt = match acpi::madt() {
+        Some(madt) if !madt.io_apics.is_empty() && cpu_has_apic() => madt,
+        _ => {
+            info!("no APIC found, using the 8259 PIC");
+            return false;
+        }
+    };
+
+    let local_apic = match map_mmio(madt.local_apic_address, 4096) {
+        Ok(addr) => addr,
+        Err(err) => {
+            warn!("cannot map the local APIC: {:?}", err);
+            return false;
+        }
+    };
+    {
+        let mut io_apics = IO_APICS.lock();
+        for (slot, info) in io_apics.iter_mut().zip(madt.io_apics.iter()) {
+            let base = match map_mmio(info.address, 4096) {
+                Ok(addr) => addr,
+                Err(err) => {
+                    warn!("cannot map I/O APIC {}: {:?}", info.id, err);
+                    continue;
+                }
+            };
+            let mut io_apic = IoApic {
+                base,
+                gsi_base: info.gsi_base,
+                entries: 0,
+            };
+            io_apic.entries = (unsafe { io_apic.read(IOAPIC_VERSION) } >> 16 & 0xff) + 1;
+            // 所有输入先屏蔽掉
+            for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+                unsafe { io_apic.set_redirection(gsi, REDIRECTION_MASKED, 0) };
+            }
+            *slot = Some(io_apic);
+        }
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if madt.legacy_pics {
+            disable_pics();
+        }
+        unsafe { enable_local_apic(local_apic) };
+        LOCAL_APIC.store(local_apic.as_u64(), Ordering::SeqCst);
+        for &irq in irqs {
+            route_isa_irq(madt, irq, vector_base + irq);
+        }
+    });
+    info!(
+        "interrupts are delivered through the APIC (id {})",
+        local_apic_id()
+    );
+    true
+}
+
+unsafe fn enable_local_apic(base: VirtAddr) {
+    let mut msr = Msr::new(IA32_APIC_BASE);
+    let value = msr.read();
+    msr.write(value | APIC_BASE_ENABLE);
+    write_local(base, LAPIC_TPR, 0);
+    write_local(
+        base,
+        LAPIC_SVR,
+        LAPIC_SVR_ENABLE | u32::from(SPURIOUS_VECTOR),
+    );
+}
+
+/// Enables the local APIC of an application processor. The registers of every
+/// processor's local APIC appear at the same address.
+pub fn init_ap() {
+    let base = LOCAL_APIC.load(Ordering::SeqCst);
+    if base != 0 {
+        unsafe { enable_local_apic(VirtAddr::new(base)) };
+    }
+}
+
+/// Sends an inter-processor interrupt to the processor with the given APIC id
+/// and waits until the local APIC accepted it. `command` is the low word of
+/// the interrupt command register.
+pub fn send_ipi(apic_id: u8, command: u32) {
+    let base = VirtAddr::new(LOCAL_APIC.load(Ordering::SeqCst));
+    unsafe {
+        write_local(base, LAPIC_ICR_HIGH, u32::from(apic_id) << 24);
+        write_local(base, LAPIC_ICR_LOW, command);
+        while read_local(base, LAPIC_ICR_LOW) & ICR_SEND_PENDING != 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+}
+
+/// Masks all inputs of both 8259 PICs. They stay initialized, so their
+/// spurious interrupts still arrive at the remapped vectors.
+fn disable_pics() {
+    use x86_64::instructions::port::Port;
+    unsafe {
+        Port::<u8>::new(0xa1).write(0xff);
+        Port::<u8>::new(0x21).write(0xff);
+    }
+}
+
+fn route_isa_irq(madt: &Madt, irq: u8, vector: u8) {
+    let (gsi, flags) = madt.isa_irq(irq);
+    let mut low = u32::from(vector) | REDIRECTION_MASKED;
+    // ISA 中断默认是高电平有效, 边沿触发
+    if flags & 0b11 == 0b11 {
+        low |= REDIRECTION_ACTIVE_LOW;
+    }
+    if (flags >> 2) & 0b11 == 0b11 {
+        low |= REDIRECTION_LEVEL;
+    }
+    let destination = local_apic_id();
+    with_io_apic(gsi, |io_apic| unsafe {
+        io_apic.set_redirection(gsi, low, destination)
+    });
+}
+
+fn with_io_apic<R>(gsi: u32, f: impl FnOnce(&IoApic) -> R) -> Option<R> {
+    let io_apics = IO_APICS.lock();
+    io_apics.iter().flatten().find(|a| a.handles(gsi)).map(f)
+}
+
+/// Masks or unmasks the legacy ISA `irq` in the I/O APIC.
+pub fn set_masked(irq: u8, masked: bool) {
+    let gsi = match acpi::madt() {
+        Some(madt) => madt.isa_irq(irq).0,
+        None => return,
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        with_io_apic(gsi, |io_apic| unsafe {
+            let register = io_apic.redirection_register(gsi);
+            let low = io_apic.read(register);
+            let low = if masked {
+                low | REDIRECTION_MASKED
+            } else {
+                low & !REDIRECTION_MASKED
+            };
+            io_apic.write(register, low);
+        });
+    });
+}
+
+/// Signals the end of the current interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    let base = LOCAL_APIC.load(Ordering::Relaxed);
+    if base != 0 {
+        unsafe { write_local(VirtAddr::new(base), LAPIC_EOI, 0) };
+    }
+}
+
+/// Returns the APIC id of the current processor, or 0 while the PICs are used.
+pub fn local_apic_id() -> u8 {
+    let base = LOCAL_APIC.load(Ordering::Relaxed);
+    if base == 0 {
+        return 0;
+    }
+    (unsafe { read_local(VirtAddr::new(base), LAPIC_ID) } >> 24) as u8
+}
+
+unsafe fn read_local(base: VirtAddr, register: u64) -> u32 {
+    ptr::read_volatile((base + register).as_ptr::<u32>())
+}
+
+unsafe fn write_local(base: VirtAddr, register: u64, value: u32) {
+    ptr::write_volatile((base + register).as_mut_ptr::<u32>(), value)
+}
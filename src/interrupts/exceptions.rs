@@ -0,0 +1,259 @@
+//! Handlers for the CPU exceptions.
+//!
+//! Every exception is reported on the screen and the serial port. Faults in a
+//! user program end the program (see `userspace`). Faults that happen while a
+//! recovery point is armed abort the running code and return to it (see
+//! `recovery`); all other faults print a backtrace and halt the CPU.
+
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+use super::recovery::{self, Fault};
+use super::trap::{self, TrapFrame};
+use crate::{backtrace, gdt, hlt_loop, println, serial_println};
+
+/// Installs the handlers of all exceptions in `idt`.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    trap::install(idt);
+    idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    // 缺页处理留在被中断代码的栈上, 这样嵌套的缺页不会覆盖外层的现场;
+    // 栈溢出时进不了缺页处理, 由 double fault 报告
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.security_exception
+        .set_handler_fn(security_exception_handler);
+}
+
+/// Prints to the screen and the serial port.
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        println!($($arg)*);
+        serial_println!($($arg)*);
+    }};
+}
+
+/// Returns whether the processor cannot go on after the fault in
+/// `stack_frame`, because it came from the kernel and no recovery point can
+/// take it.
+fn is_fatal(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 != 3 && !recovery::can_recover()
+}
+
+/// Marks the processor as panicking before a fatal fault is reported, so that
+/// the report can take over output locks the interrupted code holds.
+fn set_panicking() {
+    crate::smp::current().set_panicking();
+}
+
+fn report_exception(name: &str, stack_frame: &InterruptStackFrame, error_code: Option<u64>) {
+    report!("EXCEPTION: {}", name);
+    if let Some(code) = error_code {
+        report!("Error Code: {:#x}", code);
+    }
+    report!("{:#?}", stack_frame);
+}
+
+/// Reports a fault and ends the user program or returns to the recovery point,
+/// or halts if there is neither.
+fn fault(
+    vector: u8,
+    name: &'static str,
+    stack_frame: &mut InterruptStackFrame,
+    error_code: Option<u64>,
+) {
+    let _handler = super::enter(vector);
+    if is_fatal(stack_frame) {
+        set_panicking();
+    }
+    report_exception(name, stack_frame, error_code);
+    if crate::userspace::abort_from_exception(stack_frame, name) {
+        return;
+    }
+    let fault = Fault {
+        name,
+        instruction_pointer: stack_frame.instruction_pointer,
+        error_code,
+    };
+    if !recovery::recover(stack_frame, fault) {
+        backtrace::print_fault(stack_frame);
+        hlt_loop();
+    }
+}
+
+macro_rules! fault_handler {
+    ($handler:ident, $vector:expr, $name:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: &mut InterruptStackFrame) {
+            fault($vector, $name, stack_frame, None);
+        }
+    };
+    ($handler:ident, $vector:expr, $name:expr, error_code) => {
+        extern "x86-interrupt" fn $handler(stack_frame: &mut InterruptStackFrame, error_code: u64) {
+            fault($vector, $name, stack_frame, Some(error_code));
+        }
+    };
+}
+
+fault_handler!(divide_error_handler, 0, "DIVIDE ERROR");
+fault_handler!(overflow_handler, 4, "OVERFLOW");
+fault_handler!(bound_range_exceeded_handler, 5, "BOUND RANGE EXCEEDED");
+fault_handler!(invalid_opcode_handler, 6, "INVALID OPCODE");
+fault_handler!(device_not_available_handler, 7, "DEVICE NOT AVAILABLE");
+fault_handler!(invalid_tss_handler, 10, "INVALID TSS", error_code);
+fault_handler!(
+    segment_not_present_handler,
+    11,
+    "SEGMENT NOT PRESENT",
+    error_code
+);
+fault_handler!(
+    stack_segment_fault_handler,
+    12,
+    "STACK SEGMENT FAULT",
+    error_code
+);
+fault_handler!(
+    general_protection_fault_handler,
+    13,
+    "GENERAL PROTECTION FAULT",
+    error_code
+);
+fault_handler!(x87_floating_point_handler, 16, "X87 FLOATING POINT");
+fault_handler!(alignment_check_handler, 17, "ALIGNMENT CHECK", error_code);
+fault_handler!(simd_floating_point_handler, 19, "SIMD FLOATING POINT");
+fault_handler!(virtualization_handler, 20, "VIRTUALIZATION");
+fault_handler!(
+    security_exception_handler,
+    30,
+    "SECURITY EXCEPTION",
+    error_code
+);
+
+/// Hands the breakpoint to the GDB stub, or reports it and continues.
+pub(super) fn breakpoint_handler(frame: &mut TrapFrame) {
+    if !crate::gdbstub::handle_trap(frame) {
+        report_exception("BREAKPOINT", frame.stack_frame(), None);
+    }
+}
+
+/// Hands a finished single step to the GDB stub, or reports it and continues.
+pub(super) fn debug_handler(frame: &mut TrapFrame) {
+    if !crate::gdbstub::handle_trap(frame) {
+        report_exception("DEBUG", frame.stack_frame(), None);
+    }
+}
+
+extern "x86-interrupt" fn nmi_handler(stack_frame: &mut InterruptStackFrame) {
+    let _handler = super::enter(2);
+    report_exception("NON MASKABLE INTERRUPT", stack_frame, None);
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    let handler = super::enter(8);
+    let addr = Cr2::read();
+    let overflow = crate::memory::fault::overflowed_stack(addr, stack_frame.stack_pointer);
+    // 求值时无限递归造成的栈溢出也要能回到提示符
+    let recoverable = overflow.is_some() && recovery::can_recover();
+    if !recoverable {
+        set_panicking();
+    }
+    match overflow {
+        Some(stack) => {
+            report!("EXCEPTION: STACK OVERFLOW ({})", stack);
+            report!("Accessed Address: {:?}", addr);
+            report!("{:#?}", stack_frame);
+        }
+        None => report_exception("DOUBLE FAULT", stack_frame, Some(error_code)),
+    }
+    if recoverable {
+        let fault = Fault {
+            name: "STACK OVERFLOW",
+            instruction_pointer: stack_frame.instruction_pointer,
+            error_code: None,
+        };
+        if recovery::recover(stack_frame, fault) {
+            unsafe { recovery::return_from_handler(stack_frame, handler) }
+        }
+        set_panicking();
+    }
+    backtrace::print_fault(stack_frame);
+    hlt_loop();
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut InterruptStackFrame) -> ! {
+    let _handler = super::enter(18);
+    set_panicking();
+    report_exception("MACHINE CHECK", stack_frame, None);
+    backtrace::print_fault(stack_frame);
+    hlt_loop();
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    use crate::memory::fault::{handle_page_fault, FaultResolution};
+
+    let _handler = super::enter(14);
+    let addr = Cr2::read();
+    let resolution = handle_page_fault(addr, error_code, stack_frame.stack_pointer);
+    // 按需分配的页已经映射好了, 返回重新执行出错的指令
+    if resolution == FaultResolution::Resolved {
+        return;
+    }
+    if is_fatal(stack_frame) {
+        set_panicking();
+    }
+    let name = match resolution {
+        FaultResolution::StackOverflow(stack) => {
+            report!("EXCEPTION: STACK OVERFLOW ({})", stack);
+            "STACK OVERFLOW"
+        }
+        _ => {
+            report!("EXCEPTION: PAGE FAULT");
+            "PAGE FAULT"
+        }
+    };
+    report!("Accessed Address: {:?}", addr);
+    report!("Error Code: {:?}", error_code);
+    report!("{:#?}", stack_frame);
+    if crate::userspace::abort_from_exception(stack_frame, name) {
+        return;
+    }
+    let fault = Fault {
+        name,
+        instruction_pointer: stack_frame.instruction_pointer,
+        error_code: Some(error_code.bits()),
+    };
+    if !recovery::recover(stack_frame, fault) {
+        backtrace::print_fault(stack_frame);
+        hlt_loop();
+    }
+}
@@ -0,0 +1,176 @@
+//! Registration of handlers for the legacy IRQs.
+//!
+//! Every IRQ line has a generic entry in the IDT that calls the handlers
+//! registered for it, one after another, and then signals the end of the
+//! interrupt to whichever controller delivered it. Several devices can share a
+//! line; each handler has to check whether its device raised the interrupt.
+//! A line is unmasked when its first handler is registered and masked again
+//! when the last one is removed.
+
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+use super::{apic, enter, mask_irq, record_spurious, unmask_irq, PICS, PIC_1_OFFSET};
+
+/// The number of legacy IRQ lines.
+pub const IRQ_COUNT: usize = 16;
+/// The most handlers that can share one IRQ line.
+pub const MAX_SHARED: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// There is no such IRQ line.
+    InvalidIrq,
+    /// The line already has `MAX_SHARED` handlers.
+    Full,
+}
+
+// 存的是处理函数的地址, 0 表示空位
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLERS: [AtomicUsize; MAX_SHARED] = [EMPTY; MAX_SHARED];
+
+static HANDLERS: [[AtomicUsize; MAX_SHARED]; IRQ_COUNT] = [NO_HANDLERS; IRQ_COUNT];
+
+/// Calls `handler` on every interrupt of `irq` and unmasks the line.
+///
+/// The handler runs with interrupts disabled and must not block. It does not
+/// need to signal the end of the interrupt.
+pub fn register_irq(irq: u8, handler: fn()) -> Result<(), IrqError> {
+    let slots = HANDLERS.get(usize::from(irq)).ok_or(IrqError::InvalidIrq)?;
+    let address = handler as usize;
+    let registered = slots.iter().any(|slot| {
+        slot.compare_exchange(0, address, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    });
+    if !registered {
+        return Err(IrqError::Full);
+    }
+    unmask_irq(irq);
+    Ok(())
+}
+
+/// Removes `handler` from `irq` and masks the line if no handler is left.
+/// Returns `false` if the handler was not registered.
+pub fn unregister_irq(irq: u8, handler: fn()) -> bool {
+    let slots = match HANDLERS.get(usize::from(irq)) {
+        Some(slots) => slots,
+        None => return false,
+    };
+    let address = handler as usize;
+    let removed = slots.iter().any(|slot| {
+        slot.compare_exchange(address, 0, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    });
+    if removed && !has_handlers(irq) {
+        mask_irq(irq);
+    }
+    removed
+}
+
+fn has_handlers(irq: u8) -> bool {
+    HANDLERS[usize::from(irq)]
+        .iter()
+        .any(|slot| slot.load(Ordering::Acquire) != 0)
+}
+
+/// Unmasks the lines that have handlers, after the interrupt controller was
+/// switched.
+pub(super) fn unmask_registered() {
+    for irq in 0..IRQ_COUNT as u8 {
+        if has_handlers(irq) {
+            unmask_irq(irq);
+        }
+    }
+}
+
+/// Signals the end of interrupt `irq` to whichever controller delivered it.
+pub fn end_of_interrupt(irq: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq) };
+    }
+}
+
+fn dispatch(irq: u8) {
+    let _handler = enter(PIC_1_OFFSET + irq);
+    if is_spurious(irq) {
+        record_spurious();
+        return;
+    }
+    for slot in HANDLERS[usize::from(irq)].iter() {
+        let address = slot.load(Ordering::Acquire);
+        if address != 0 {
+            let handler: fn() = unsafe { mem::transmute(address) };
+            handler();
+        }
+    }
+    end_of_interrupt(irq);
+}
+
+/// Recognizes the spurious interrupts of the 8259 PICs, which arrive as IRQ 7
+/// or 15 without the bit in the in-service register.
+fn is_spurious(irq: u8) -> bool {
+    const READ_ISR: u8 = 0x0b;
+
+    if apic::is_enabled() || (irq != 7 && irq != 15) {
+        return false;
+    }
+    // 两个片上都是各自的最后一根线
+    let command = if irq == 7 { 0x20 } else { 0xa0 };
+    let mut port = Port::<u8>::new(command);
+    let in_service = unsafe {
+        port.write(READ_ISR);
+        port.read()
+    };
+    if in_service & 0x80 != 0 {
+        return false;
+    }
+    // 主片收到的级联中断是真的, 还要给主片 EOI
+    if irq == 15 {
+        end_of_interrupt(2);
+    }
+    true
+}
+
+macro_rules! irq_stubs {
+    ($($irq:expr => $stub:ident),*) => {
+        $(
+            extern "x86-interrupt" fn $stub(_stack_frame: &mut InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        static STUBS: [HandlerFunc; IRQ_COUNT] = [$($stub),*];
+    };
+}
+
+irq_stubs!(
+    0 => irq0_stub,
+    1 => irq1_stub,
+    2 => irq2_stub,
+    3 => irq3_stub,
+    4 => irq4_stub,
+    5 => irq5_stub,
+    6 => irq6_stub,
+    7 => irq7_stub,
+    8 => irq8_stub,
+    9 => irq9_stub,
+    10 => irq10_stub,
+    11 => irq11_stub,
+    12 => irq12_stub,
+    13 => irq13_stub,
+    14 => irq14_stub,
+    15 => irq15_stub
+);
+
+/// Installs the entries of all IRQ lines in `idt`.
+pub(super) fn install(idt: &mut InterruptDescriptorTable) {
+    for (irq, &stub) in STUBS.iter().enumerate() {
+        idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(stub);
+    }
+}
@@ -0,0 +1,146 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use lazy_static::lazy_static;
+use pic8259_simple::ChainedPics;
+
+use crate::smp;
+use crate::sync::Mutex;
+
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+//删除枚举
+pub const BACK_SPACE: u8 = 8;
+
+pub mod apic;
+pub mod exceptions;
+pub mod irq;
+pub mod recovery;
+pub mod trap;
+
+pub use irq::{end_of_interrupt, register_irq, unregister_irq, IrqError};
+
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        exceptions::install(&mut idt);
+        irq::install(&mut idt);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+        idt
+    };
+}
+
+// 每个向量发生的次数
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static VECTOR_COUNTS: [AtomicU64; 256] = [ZERO; 256];
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Counts one interrupt or exception on `vector` and marks its handler as
+/// running on this processor until the returned value is dropped. Called
+/// first by every handler.
+pub fn enter(vector: u8) -> Handler {
+    VECTOR_COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+    let cpu = smp::current_index();
+    smp::cpu(cpu).interrupt_depth.fetch_add(1, Ordering::Relaxed);
+    Handler { cpu }
+}
+
+/// A running interrupt or exception handler, see `enter`.
+pub struct Handler {
+    cpu: usize,
+}
+
+impl Drop for Handler {
+    fn drop(&mut self) {
+        smp::cpu(self.cpu)
+            .interrupt_depth
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of interrupt and exception handlers that are running
+/// on this processor, nested in each other.
+pub fn interrupt_depth() -> usize {
+    smp::current().interrupt_depth.load(Ordering::Relaxed)
+}
+
+/// Returns how often `vector` fired since boot.
+pub fn vector_count(vector: u8) -> u64 {
+    VECTOR_COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+/// Returns the vectors that fired at least once, with their counts.
+pub fn vector_counts() -> impl Iterator<Item = (u8, u64)> {
+    (0..=255u8)
+        .map(|vector| (vector, vector_count(vector)))
+        .filter(|&(_, count)| count != 0)
+}
+
+/// Returns the number of spurious interrupts of the PICs and the local APIC.
+/// They are also counted on their vectors.
+pub fn spurious_count() -> u64 {
+    SPURIOUS_COUNT.load(Ordering::Relaxed)
+}
+
+fn record_spurious() {
+    SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn init_idt() {
+    IDT.load();
+}
+
+pub static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// Moves interrupt delivery to the APICs if there are any, keeping the 8259
+/// PICs otherwise, and unmasks the IRQs that have handlers.
+pub fn init_controller() {
+    // IRQ 2 是两片 8259 之间的级联, 不接设备
+    let irqs: [u8; 15] = [0, 1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+    apic::init(PIC_1_OFFSET, &irqs);
+    irq::unmask_registered();
+}
+
+/// Allows the legacy `irq` to be delivered.
+pub fn unmask_irq(irq: u8) {
+    set_irq_masked(irq, false);
+}
+
+/// Stops the legacy `irq` from being delivered.
+pub fn mask_irq(irq: u8) {
+    set_irq_masked(irq, true);
+}
+
+fn set_irq_masked(irq: u8, masked: bool) {
+    use x86_64::instructions::port::Port;
+
+    if apic::is_enabled() {
+        return apic::set_masked(irq, masked);
+    }
+    // 从片的中断要经过主片的 IRQ 2
+    if irq >= 8 && !masked {
+        set_irq_masked(2, false);
+    }
+    // 主片 IRQ 0-7, 从片 IRQ 8-15
+    let (mut port, bit) = if irq < 8 {
+        (Port::<u8>::new(0x21), irq)
+    } else {
+        (Port::<u8>::new(0xa1), irq - 8)
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mask = port.read();
+        if masked {
+            port.write(mask | 1 << bit);
+        } else {
+            port.write(mask & !(1 << bit));
+        }
+    });
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    let _handler = enter(apic::SPURIOUS_VECTOR);
+    record_spurious();
+}
@@ -0,0 +1,194 @@
+//! Recovery from CPU exceptions.
+//!
+//! `catch_faults` arms a recovery point with a small setjmp/longjmp pair. When
+//! an exception handler finds an armed recovery point, it does not halt but
+//! rewrites the interrupted context so that `iretq` continues in
+//! `resume_after_fault`, which jumps back into `catch_faults`. The code that
+//! faulted is abandoned without running destructors, so anything it owned is
+//! leaked.
+//!
+//! Recovery points are per processor, and a fault only returns to one if it
+//! happened in the same interrupt context that armed it: jumping out of a
+//! nested interrupt handler would skip its end of interrupt. A fault while the
+//! code holds a kernel lock that it did not hold when arming is not recovered
+//! either, because the lock would never be released.
+
+use core::ptr;
+use core::sync::atomic::Ordering;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+use crate::{smp, sync};
+
+/// Callee-saved registers, stack pointer and return address of a recovery point.
+#[repr(C)]
+#[derive(Default)]
+struct JumpBuffer {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+    rip: u64,
+}
+
+global_asm!(
+    r#"
+    .global recovery_setjmp
+recovery_setjmp:
+    mov %rbx, 0(%rdi)
+    mov %rbp, 8(%rdi)
+    mov %r12, 16(%rdi)
+    mov %r13, 24(%rdi)
+    mov %r14, 32(%rdi)
+    mov %r15, 40(%rdi)
+    lea 8(%rsp), %rdx
+    mov %rdx, 48(%rdi)
+    mov (%rsp), %rdx
+    mov %rdx, 56(%rdi)
+    xor %eax, %eax
+    ret
+
+    .global recovery_longjmp
+recovery_longjmp:
+    mov 0(%rdi), %rbx
+    mov 8(%rdi), %rbp
+    mov 16(%rdi), %r12
+    mov 24(%rdi), %r13
+    mov 32(%rdi), %r14
+    mov 40(%rdi), %r15
+    mov 48(%rdi), %rsp
+    mov $1, %eax
+    jmp *56(%rdi)
+"#
+);
+
+extern "C" {
+    fn recovery_setjmp(buffer: *mut JumpBuffer) -> u64;
+    fn recovery_longjmp(buffer: *const JumpBuffer) -> !;
+}
+
+/// An exception that aborted the code run by `catch_faults`.
+#[derive(Debug, Clone, Copy)]
+pub struct Fault {
+    pub name: &'static str,
+    pub instruction_pointer: VirtAddr,
+    pub error_code: Option<u64>,
+}
+
+/// A recovery point on the stack of `catch_faults`.
+#[repr(C)]
+struct RecoveryPoint {
+    buffer: JumpBuffer,
+    /// `interrupt_depth` when it was armed.
+    depth: usize,
+    /// `sync::locks_held` when it was armed.
+    locks_held: usize,
+    interrupts_enabled: bool,
+    /// Set by `recover` before it jumps back.
+    fault: Option<Fault>,
+}
+
+/// Runs `f` and returns its result, or the exception that aborted it.
+///
+/// Calls can be nested; a fault returns to the innermost one on the same
+/// processor. Interrupts are enabled or disabled again as they were on entry.
+#[inline(never)]
+pub fn catch_faults<R>(f: impl FnOnce() -> R) -> Result<R, Fault> {
+    let cpu = smp::current();
+    let mut point = RecoveryPoint {
+        buffer: JumpBuffer::default(),
+        depth: super::interrupt_depth(),
+        locks_held: sync::locks_held(),
+        interrupts_enabled: interrupts::are_enabled(),
+        fault: None,
+    };
+    let previous = cpu
+        .recovery_point
+        .swap(&mut point as *mut RecoveryPoint as usize, Ordering::SeqCst);
+    if unsafe { recovery_setjmp(&mut point.buffer) } == 0 {
+        let result = f();
+        cpu.recovery_point.store(previous, Ordering::SeqCst);
+        Ok(result)
+    } else {
+        // 从异常处理函数跳回来的, 中断是关着的
+        cpu.recovery_point.store(previous, Ordering::SeqCst);
+        let fault = unsafe { ptr::read_volatile(&point.fault) };
+        if point.interrupts_enabled {
+            interrupts::enable();
+        }
+        Err(fault.expect("recovered without a recorded fault"))
+    }
+}
+
+/// Redirects the interrupted context to the innermost recovery point, so that
+/// returning from the exception handler resumes in `catch_faults`.
+///
+/// Must be called by the exception handler of the fault. Returns `false` if no
+/// recovery point is armed on this processor, or if the fault cannot return
+/// to it safely.
+pub fn recover(stack_frame: &mut InterruptStackFrame, fault: Fault) -> bool {
+    let point = match recovery_point() {
+        Some(point) => point,
+        None => return false,
+    };
+    point.fault = Some(fault);
+    // 在恢复点的栈上留出一点空间, 按函数入口的要求对齐
+    let stack_pointer = ((point.buffer.rsp - 256) & !0xf) - 8;
+    unsafe {
+        let frame = stack_frame.as_mut();
+        frame.instruction_pointer = VirtAddr::new(resume_after_fault as usize as u64);
+        frame.stack_pointer = VirtAddr::new(stack_pointer);
+        // 跳回去之前不能被中断, 中断状态由 catch_faults 恢复
+        frame.cpu_flags &= !RFLAGS_INTERRUPT;
+    }
+    true
+}
+
+/// Returns from an exception handler that cannot return normally, like the
+/// double fault handler, into the context that `recover` prepared in its
+/// `stack_frame`. `handler` is the guard of the calling handler.
+///
+/// This function is unsafe because `stack_frame` must be the frame of the
+/// calling handler, `recover` must have succeeded on it, and the handler must
+/// own nothing else that needs to be dropped.
+pub unsafe fn return_from_handler(stack_frame: &InterruptStackFrame, handler: super::Handler) -> ! {
+    drop(handler);
+    // 栈指针指向处理函数收到的现场, iretq 按 recover 改过的内容返回
+    llvm_asm!("mov $0, %rsp; iretq"
+        :: "r"(stack_frame as *const InterruptStackFrame)
+        : "memory" : "volatile");
+    core::hint::unreachable_unchecked()
+}
+
+/// Returns whether `recover` would succeed for a fault that the calling
+/// exception handler is handling.
+pub fn can_recover() -> bool {
+    recovery_point().is_some()
+}
+
+fn recovery_point() -> Option<&'static mut RecoveryPoint> {
+    let point = smp::current().recovery_point.load(Ordering::SeqCst) as *mut RecoveryPoint;
+    if point.is_null() {
+        return None;
+    }
+    let point = unsafe { &mut *point };
+    // 出错的代码要和 catch_faults 在同一层, 这一层是异常处理函数自己
+    if super::interrupt_depth() != point.depth + 1 {
+        return None;
+    }
+    if sync::locks_held() != point.locks_held {
+        return None;
+    }
+    Some(point)
+}
+
+const RFLAGS_INTERRUPT: u64 = 1 << 9;
+
+extern "C" fn resume_after_fault() -> ! {
+    let point = smp::current().recovery_point.load(Ordering::SeqCst) as *const RecoveryPoint;
+    unsafe { recovery_longjmp(&(*point).buffer) }
+}
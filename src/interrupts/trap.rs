@@ -0,0 +1,136 @@
+//! Entry points for the debug exceptions that save all general purpose
+//! registers.
+//!
+//! `extern "x86-interrupt"` handlers only see the interrupt stack frame, but a
+//! debugger has to read and change every register of the interrupted code. The
+//! breakpoint and debug exceptions therefore enter through the assembly stubs
+//! below, which push the registers into a `TrapFrame`, call `trap_dispatch`
+//! and restore the possibly changed registers before returning.
+
+use core::mem;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+use super::exceptions;
+
+/// The registers of the interrupted code, in the order the stubs push them.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    // 以下是 CPU 压入的中断栈帧
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// Returns the part of the frame that the CPU pushed.
+    pub fn stack_frame(&self) -> &InterruptStackFrame {
+        unsafe { &*(&self.rip as *const u64 as *const InterruptStackFrame) }
+    }
+}
+
+global_asm!(
+    r#"
+    .global debug_trap_entry
+debug_trap_entry:
+    push $0
+    push $1
+    jmp trap_common
+
+    .global breakpoint_trap_entry
+breakpoint_trap_entry:
+    push $0
+    push $3
+    jmp trap_common
+
+trap_common:
+    push %rax
+    push %rbx
+    push %rcx
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rbp
+    push %r8
+    push %r9
+    push %r10
+    push %r11
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    cld
+    mov %rsp, %rdi
+    # rbx 已经保存在帧里, 用它记住对齐之前的栈
+    mov %rsp, %rbx
+    and $~0xf, %rsp
+    call trap_dispatch
+    mov %rbx, %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %r11
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rbp
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %rcx
+    pop %rbx
+    pop %rax
+    add $16, %rsp
+    iretq
+"#
+);
+
+extern "C" {
+    fn debug_trap_entry();
+    fn breakpoint_trap_entry();
+}
+
+/// Installs the stubs for the debug and breakpoint exceptions in `idt`.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    // 描述符里只存地址, 调用约定由汇编自己保证
+    unsafe {
+        idt.debug
+            .set_handler_fn(mem::transmute::<unsafe extern "C" fn(), HandlerFunc>(
+                debug_trap_entry,
+            ));
+        idt.breakpoint
+            .set_handler_fn(mem::transmute::<unsafe extern "C" fn(), HandlerFunc>(
+                breakpoint_trap_entry,
+            ));
+    }
+}
+
+#[no_mangle]
+extern "C" fn trap_dispatch(frame: &mut TrapFrame) {
+    let _handler = super::enter(frame.vector as u8);
+    match frame.vector {
+        1 => exceptions::debug_handler(frame),
+        3 => exceptions::breakpoint_handler(frame),
+        vector => unreachable!("no trap handler for vector {}", vector),
+    }
+}
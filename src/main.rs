@@ -1,31 +1,41 @@
 #![no_std]
 #![feature(llvm_asm)]
+#![feature(global_asm)]
 #![no_main]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
 #![feature(box_syntax)]
 #![feature(wake_trait)]
+#![feature(const_in_array_repeat_expressions)]
 extern crate alloc;
 extern crate rlibc;
 
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use linked_list_allocator::LockedHeap;
+use allocator::{fixed_size_block::FixedSizeBlockAllocator, Locked};
 use log::*;
 use task::{executor::Executor, Task};
 
+pub mod acpi;
 pub mod allocator;
+pub mod backtrace;
+pub mod gdbstub;
 pub mod gdt;
 pub mod interrupts;
 pub mod mal;
 pub mod memory;
 pub mod serial;
+pub mod smp;
 pub mod stdio;
+pub mod sync;
 pub mod task;
+pub mod time;
+pub mod userspace;
 mod vga_buffer;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> =
+    Locked::new(FixedSizeBlockAllocator::new());
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
@@ -35,7 +45,12 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 /// 这个函数将在panic时被调用
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+    // 不会再回到被打断的代码, 可以接管它拿着的输出锁
+    smp::current().set_panicking();
     println!("{}", info);
+    serial_println!("{}", info);
+    backtrace::print();
     hlt_loop();
 }
 
@@ -43,22 +58,44 @@ fn panic(info: &PanicInfo) -> ! {
 entry_point!(kernel_main);
 
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    use memory::BootInfoFrameAllocator;
+    use memory::BitmapFrameAllocator;
     use x86_64::structures::paging::mapper::MapperAllSizes;
     use x86_64::structures::paging::Page;
     use x86_64::VirtAddr;
     // println!("Hello World {}", ",my friends!");
     init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    memory::init_memory_map(&boot_info.memory_map);
     // new: initialize a mapper
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    // 其他处理器的启动代码必须放在 1 MiB 以下, 趁低端内存还没分配出去先留一页
+    let trampoline = frame_allocator.allocate_frame_below(x86_64::PhysAddr::new(0x10_0000));
+    info!(
+        "physical memory: {} of {} frames free",
+        frame_allocator.free_frames(),
+        frame_allocator.total_frames()
+    );
+    memory::install(mapper, frame_allocator);
     // init heap 初始化堆
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    allocator::init_heap().expect("heap initialization failed");
+    // 换成有保护页的中断栈, 启动栈下面 bootloader 留了一个不映射的保护页
+    gdt::init_percpu();
+    match memory::fault::register_current_stack_guard("boot stack") {
+        Ok(guard) => info!("boot stack guard page at {:?}", guard),
+        Err(err) => warn!("boot stack has no guard page: {:?}", err),
+    }
+    // 有 APIC 就换掉 8259
+    acpi::init();
+    interrupts::init_controller();
+    // 启动其他处理器
+    smp::init(trampoline);
 
     // 启动任务执行器
     let mut executor = Executor::new();
     executor.spawn(Task::new(mal::shell::mal_repl()));
+    executor.spawn(Task::new(mal::shell::serial_repl()));
     executor.run();
     hlt_loop();
 }
@@ -75,10 +112,17 @@ pub fn init() {
     interrupts::init_idt();
     // 设置段表和 TSS
     gdt::init();
+    // 打开 syscall 指令
+    userspace::syscall::init();
     // init log
     init_log();
     // PICS(中断控制器) 初始化
     unsafe { interrupts::PICS.lock().initialize() };
+    // 设置时钟中断的频率
+    time::init();
+    // 键盘和串口的中断
+    task::keyboard::init();
+    task::serial::init();
     // 允许时间中断
     x86_64::instructions::interrupts::enable();
 
@@ -93,7 +137,18 @@ fn init_log() {
         }
 
         fn log(&self, record: &Record) {
-            println!("[{:>5}] {}", record.level(), record.args());
+            // 读过 RTC 之后带上时间
+            match time::rtc::now() {
+                Some(now) => println!(
+                    "[{:02}:{:02}:{:02} {:>5}] {}",
+                    now.hour,
+                    now.minute,
+                    now.second,
+                    record.level(),
+                    record.args()
+                ),
+                None => println!("[{:>5}] {}", record.level(), record.args()),
+            }
         }
 
         fn flush(&self) {}
@@ -10,10 +10,12 @@ use crate::mal::env::{env_set,env_sets};
 use crate::mal::rep;
 use crate::vec;
 use crate::list;
-use crate::println;
+use crate::stdout_println;
 use crate::vector;
 use crate::mal::reader::read_str;
 use crate::mal::printer::pr_seq;
+use crate::format;
+use hashbrown::HashMap;
 
 // 处理两个值入参
 macro_rules! fn_t_int_int {
@@ -189,6 +191,223 @@ fn vals(a: MalArgs) -> MalRet {
     }
 }
 
+// 用关键字作为键生成 hash-map, 内核的状态信息都用这种形式返回
+fn kw_map(kvs: Vec<(&str, MalVal)>) -> MalVal {
+    let mut hm: HashMap<String, MalVal> = HashMap::new();
+    for (k, v) in kvs {
+        hm.insert(format!("\u{29e}{}", k), v);
+    }
+    Hash(Rc::new(hm), Rc::new(Nil))
+}
+
+// 每个 size class 的命中/未命中次数
+fn slab_stats(_a: MalArgs) -> MalRet {
+    let (classes, large) = crate::allocator::size_class_stats();
+    let classes: Vec<MalVal> = classes
+        .iter()
+        .map(|c| {
+            kw_map(vec![
+                ("size", Int(c.block_size as i64)),
+                ("hits", Int(c.hits as i64)),
+                ("misses", Int(c.misses as i64)),
+            ])
+        })
+        .collect();
+    Ok(kw_map(vec![
+        ("classes", list!(classes)),
+        ("large-allocs", Int(large as i64)),
+    ]))
+}
+
+// 堆和物理内存的使用情况
+fn mem_stats(_a: MalArgs) -> MalRet {
+    let heap = crate::allocator::heap_stats();
+    let mut stats = vec![
+        ("heap-size", Int(heap.size as i64)),
+        ("heap-max", Int(heap.max_size as i64)),
+        ("heap-used", Int(heap.used as i64)),
+        ("heap-free", Int(heap.free as i64)),
+        ("heap-peak", Int(heap.peak as i64)),
+        ("allocs", Int(heap.allocations as i64)),
+        ("frees", Int(heap.deallocations as i64)),
+    ];
+    if let Some(frames) = crate::memory::frame_stats() {
+        stats.push(("frames-total", Int(frames.total as i64)));
+        stats.push(("frames-free", Int(frames.free as i64)));
+        stats.push(("frames-used", Int(frames.used as i64)));
+    }
+    Ok(kw_map(stats))
+}
+
+// 把 Debug 输出的类型名转换成关键字的形式, 比如 KernelStack => kernel-stack
+fn kebab_case(name: &str) -> String {
+    let mut out = String::new();
+    for c in name.chars() {
+        match c {
+            'A'..='Z' => {
+                if !out.is_empty() {
+                    out.push('-');
+                }
+                out.push(c.to_ascii_lowercase());
+            }
+            '(' => out.push('-'),
+            ')' => {}
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// bootloader 传过来的物理内存分布
+fn memory_map(_a: MalArgs) -> MalRet {
+    let map = match crate::memory::memory_map() {
+        Some(map) => map,
+        None => return error("memory map not available"),
+    };
+    let regions: Vec<MalVal> = map
+        .iter()
+        .map(|r| {
+            let start = r.range.start_addr();
+            let end = r.range.end_addr();
+            kw_map(vec![
+                ("start", Int(start as i64)),
+                ("end", Int(end as i64)),
+                ("size", Int((end - start) as i64)),
+                (
+                    "type",
+                    Str(format!("\u{29e}{}", kebab_case(&format!("{:?}", r.region_type)))),
+                ),
+            ])
+        })
+        .collect();
+    Ok(list!(regions))
+}
+
+// 开机以来的毫秒数
+fn uptime(_a: MalArgs) -> MalRet {
+    Ok(Int(crate::time::uptime_ms() as i64))
+}
+
+fn ticks(_a: MalArgs) -> MalRet {
+    Ok(Int(crate::time::ticks() as i64))
+}
+
+// 纳秒级的时间, 用来测量代码的耗时
+fn time_ns(_a: MalArgs) -> MalRet {
+    Ok(Int(crate::time::now_ns() as i64))
+}
+
+// 当前的日期和时间 (UTC)
+fn now(_a: MalArgs) -> MalRet {
+    let now = match crate::time::rtc::now() {
+        Some(now) => now,
+        None => return error("real-time clock not available"),
+    };
+    Ok(kw_map(vec![
+        ("year", Int(now.year as i64)),
+        ("month", Int(now.month as i64)),
+        ("day", Int(now.day as i64)),
+        ("hour", Int(now.hour as i64)),
+        ("minute", Int(now.minute as i64)),
+        ("second", Int(now.second as i64)),
+        ("weekday", Int(now.weekday() as i64)),
+    ]))
+}
+
+fn unix_time(_a: MalArgs) -> MalRet {
+    match crate::time::rtc::unix_time() {
+        Some(seconds) => Ok(Int(seconds as i64)),
+        None => error("real-time clock not available"),
+    }
+}
+
+// 把 Unix 时间格式化成 YYYY-MM-DD HH:MM:SS, 不传参数就是当前时间
+fn format_time(a: MalArgs) -> MalRet {
+    use crate::time::rtc::DateTime;
+    let time = match a.get(0) {
+        None => crate::time::rtc::now(),
+        Some(Int(seconds)) if *seconds >= 0 => Some(DateTime::from_unix(*seconds as u64)),
+        Some(_) => return error("format-time expects a unix time"),
+    };
+    match time {
+        Some(time) => Ok(Str(format!("{}", time))),
+        None => error("real-time clock not available"),
+    }
+}
+
+// 中断和异常的次数, 以及键盘丢掉的输入
+fn irq_stats(_a: MalArgs) -> MalRet {
+    use crate::interrupts::{spurious_count, vector_count, vector_counts, PIC_1_OFFSET};
+    let vectors: Vec<MalVal> = vector_counts()
+        .map(|(vector, count)| {
+            kw_map(vec![
+                ("vector", Int(vector as i64)),
+                ("count", Int(count as i64)),
+            ])
+        })
+        .collect();
+    let dropped = crate::task::keyboard::dropped_input();
+    Ok(kw_map(vec![
+        ("timer", Int(vector_count(PIC_1_OFFSET) as i64)),
+        ("keyboard", Int(vector_count(PIC_1_OFFSET + 1) as i64)),
+        ("serial", Int(vector_count(PIC_1_OFFSET + 4) as i64)),
+        ("spurious", Int(spurious_count() as i64)),
+        ("keyboard-queue-full", Int(dropped.queue_full as i64)),
+        ("keyboard-uninitialized", Int(dropped.uninitialized as i64)),
+        ("vectors", list!(vectors)),
+    ]))
+}
+
+// 正在运行的处理器个数
+fn cpus(_a: MalArgs) -> MalRet {
+    Ok(Int(crate::smp::online_cpus() as i64))
+}
+
+// 在用户态运行演示程序, 返回它的退出码
+fn user_demo(_a: MalArgs) -> MalRet {
+    use crate::userspace::UserError;
+    match crate::userspace::run_demo() {
+        Ok(code) => Ok(Int(code as i64)),
+        Err(UserError::Fault(name)) => error(&format!("user program killed by {}", name)),
+        Err(err) => error(&format!("cannot run user program: {:?}", err)),
+    }
+}
+
+// 停在 COM2 上的 GDB 调试桩里, 等 GDB 继续执行
+fn gdb(_a: MalArgs) -> MalRet {
+    crate::gdbstub::breakpoint();
+    Ok(Nil)
+}
+
+// 切换键盘布局, 例如 (set-keyboard-layout :de)
+fn set_keyboard_layout(a: MalArgs) -> MalRet {
+    use crate::task::layout::{set_layout, Layout};
+    let name = match a.get(0) {
+        Some(Str(s)) => s.trim_start_matches('\u{29e}'),
+        _ => return error("set-keyboard-layout expects a keyword"),
+    };
+    match Layout::from_name(name) {
+        Some(layout) => {
+            set_layout(layout);
+            Ok(Nil)
+        }
+        None => {
+            let names: Vec<String> = Layout::names().map(|n| format!(":{}", n)).collect();
+            error(&format!(
+                "unknown keyboard layout {}, expected one of {}",
+                name,
+                names.join(" ")
+            ))
+        }
+    }
+}
+
+// 当前的键盘布局
+fn keyboard_layout(_a: MalArgs) -> MalRet {
+    let name = crate::task::layout::layout().name();
+    Ok(Str(format!("\u{29e}{}", name)))
+}
+
 pub fn ns() -> Vec<(&'static str,MalVal)> {
     vec![
         ("=", func(|a| Ok(Bool(a[0] == a[1])))),
@@ -203,7 +422,7 @@ pub fn ns() -> Vec<(&'static str,MalVal)> {
         ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
         ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
         ("prn",func(|a|{
-            println!("{}",pr_seq(&a, true, "", "", ""));
+            stdout_println!("{}",pr_seq(&a, true, "", "", ""));
             Ok(Nil)
         })),
         ("cons",func(cons)),
@@ -263,6 +482,22 @@ pub fn ns() -> Vec<(&'static str,MalVal)> {
         ("contains?",func(contains_q)),
         ("keys",func(keys)),
         ("vals",func(vals)),
+        // 内核状态
+        ("slab-stats",func(slab_stats)),
+        ("mem-stats",func(mem_stats)),
+        ("memory-map",func(memory_map)),
+        ("uptime",func(uptime)),
+        ("ticks",func(ticks)),
+        ("time-ns",func(time_ns)),
+        ("now",func(now)),
+        ("unix-time",func(unix_time)),
+        ("format-time",func(format_time)),
+        ("irq-stats",func(irq_stats)),
+        ("cpus",func(cpus)),
+        ("user-demo",func(user_demo)),
+        ("gdb",func(gdb)),
+        ("set-keyboard-layout",func(set_keyboard_layout)),
+        ("keyboard-layout",func(keyboard_layout)),
     ]
 }
 
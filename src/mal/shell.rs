@@ -1,22 +1,24 @@
+use crate::clear_screen;
 use crate::del;
+use crate::format;
+use crate::interrupts::recovery::catch_faults;
 use crate::mal::env::env_new;
 use crate::mal::env::Env;
 use crate::mal::rep;
 use crate::mal::types::format_error;
 use crate::print;
 use crate::println;
-use crate::task::keyboard::ScancodeStream;
+use crate::stdio::{with_stdout, Stdout};
+use crate::task::keyboard::{self, KeyEvent, ScancodeStream};
+use crate::task::serial::SerialStream;
+use crate::{serial_print, serial_println};
+use alloc::string::String;
+use alloc::vec::Vec;
 use futures_util::stream::StreamExt;
-use pc_keyboard::{
-    layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1, ScancodeSet2,
-};
+use pc_keyboard::{DecodedKey, KeyCode};
 
 pub async fn mal_repl() {
     let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
-
-    // 输入字符串缓存
-    let mut downContrl: bool = false;
 
     // 初始化环境
     let kernel_env: Env = env_new(None);
@@ -25,74 +27,179 @@ pub async fn mal_repl() {
     load_core(&kernel_env);
     print!("[IN]:");
     while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => {
-                        //todo 不同的值不同处理
-                        match character {
-                            'c' => {
-                                if downContrl {
-                                    // 如果遇到了 Ctrl+C 清空输入换行
-                                    crate::stdio::STDIN.clear();
-                                    println!();
-                                    downContrl = false;
-                                } else {
-                                    crate::stdio::STDIN.push(character);
-                                    print!("{}", character);
-                                }
-                            }
-                            '\n' => {
-                                // println!("runing: {}", crate::stdio::STDIN.to_string().as_str());
-                                println!();
-                                match rep(crate::stdio::STDIN.to_string().as_str(), &kernel_env) {
-                                    Ok(out) => println!(">>:{}", out),
-                                    Err(e) => println!(">>:{}", format_error(e)),
-                                }
-                                crate::stdio::STDIN.clear();
-                                print!("[IN]:");
-                            }
-                            // 删除和回退
-                            '\u{0008}' | '\u{007f}' => {
-                                if crate::stdio::STDIN.len() > 0 {
-                                    crate::stdio::STDIN.back_spacse();
-                                    del!();
-                                }
-                            }
-                            _ => {
-                                crate::stdio::STDIN.push(character);
-                                print!("{}", character);
-                            }
-                        }
+        let (key, modifiers) = match keyboard::decode(scancode) {
+            Some(KeyEvent {
+                key: Some(key),
+                modifiers,
+                ..
+            }) => (key, modifiers),
+            _ => continue,
+        };
+        match key {
+            // Ctrl 组合键
+            DecodedKey::Unicode(character) if modifiers.ctrl => {
+                match character.to_ascii_lowercase() {
+                    'c' => {
+                        // 清空输入换行
+                        crate::stdio::STDIN.clear();
+                        println!("^C");
+                        print!("[IN]:");
                     }
-                    DecodedKey::RawKey(key) => match key {
-                        KeyCode::Backspace | KeyCode::Delete => {
-                            crate::stdio::STDIN.back_spacse();
-                            // todo 要显示的内容也要删除
-                            del!();
-                            print!("del!");
-                        }
-                        KeyCode::ControlLeft | KeyCode::ControlRight => {
-                            downContrl = true;
-                        }
-                        KeyCode::Enter => {
-                            println!("runing: {}", crate::stdio::STDIN.to_string().as_str());
-                            // 程序代码读到 mal 中求值
-                            match rep(crate::stdio::STDIN.to_string().as_str(), &kernel_env) {
-                                Ok(out) => println!(">>:{}", out),
-                                Err(e) => println!(">>:{}", format_error(e)),
-                            }
-                            crate::stdio::STDIN.clear();
-                            print!("[IN]:");
-                        }
-                        _ => {
-                            println!("in Raw key!");
-                            print!("{:?}", key)
+                    'l' => {
+                        // 清屏, 保留正在输入的内容
+                        clear_screen!();
+                        print!("[IN]:{}", crate::stdio::STDIN.to_string());
+                    }
+                    'd' if crate::stdio::STDIN.len() == 0 => {
+                        // 屏幕上的 REPL 不能结束, 保留环境, 只给个提示
+                        println!("^D");
+                        println!("the screen REPL keeps running; definitions are kept");
+                        print!("[IN]:");
+                    }
+                    _ => {}
+                }
+            }
+            // Alt+退格 删除一个词
+            DecodedKey::Unicode('\u{0008}') | DecodedKey::Unicode('\u{007f}') if modifiers.alt => {
+                delete_word();
+            }
+            // 其他 Alt 组合键没有绑定, 当作普通按键输入
+            DecodedKey::Unicode(character) => match character {
+                '\n' => {
+                    println!();
+                    eval_line(&kernel_env);
+                    crate::stdio::STDIN.clear();
+                    print!("[IN]:");
+                }
+                // 删除和回退
+                '\u{0008}' | '\u{007f}' => {
+                    if crate::stdio::STDIN.len() > 0 {
+                        crate::stdio::STDIN.back_spacse();
+                        del!();
+                    }
+                }
+                _ => {
+                    crate::stdio::STDIN.push(character);
+                    print!("{}", character);
+                }
+            },
+            DecodedKey::RawKey(key) => match key {
+                KeyCode::Backspace | KeyCode::Delete => {
+                    if crate::stdio::STDIN.len() > 0 {
+                        crate::stdio::STDIN.back_spacse();
+                        del!();
+                    }
+                }
+                KeyCode::Enter => {
+                    println!();
+                    // 程序代码读到 mal 中求值
+                    eval_line(&kernel_env);
+                    crate::stdio::STDIN.clear();
+                    print!("[IN]:");
+                }
+                // todo 方向键等还没有处理
+                _ => {}
+            },
+        }
+    }
+}
+
+// 删掉光标前的一个词, 连同它前面的空白和括号
+fn delete_word() {
+    let is_delimiter = |c: char| c.is_whitespace() || "()[]{}".contains(c);
+    let stdin = &crate::stdio::STDIN;
+    while stdin.last().map_or(false, is_delimiter) {
+        stdin.back_spacse();
+        del!();
+    }
+    while stdin.last().map_or(false, |c| !is_delimiter(c)) {
+        stdin.back_spacse();
+        del!();
+    }
+}
+
+fn eval_line(env: &Env) {
+    let line = crate::stdio::STDIN.to_string();
+    println!(">>:{}", eval(line.as_str(), env));
+}
+
+// 求值时出现 CPU 异常就放弃这次求值, 回到提示符
+fn eval(line: &str, env: &Env) -> String {
+    match catch_faults(|| rep(line, env)) {
+        Ok(Ok(out)) => out,
+        Ok(Err(e)) => format_error(e),
+        Err(fault) => format!(
+            "evaluation aborted by {} at {:?}",
+            fault.name, fault.instruction_pointer
+        ),
+    }
+}
+
+/// A second REPL that reads from and answers on COM1, so the shell can be
+/// driven from the host with `-serial stdio`. What the evaluated code prints
+/// goes to COM1 as well.
+pub async fn serial_repl() {
+    let mut bytes = SerialStream::new();
+    // 每个 REPL 有自己的环境和输入缓存
+    let env: Env = env_new(None);
+    with_stdout(Stdout::Serial, || crate::mal::core::load_core(&env));
+    let mut line = String::new();
+    // 还没收齐的 UTF-8 字节
+    let mut pending: Vec<u8> = Vec::new();
+    let mut last = 0u8;
+
+    serial_println!();
+    serial_println!("juner_os mal shell on COM1");
+    serial_print!("[IN]:");
+    while let Some(byte) = bytes.next().await {
+        match byte {
+            // 终端可能发 \r, \n 或者 \r\n
+            b'\n' if last == b'\r' => {}
+            b'\r' | b'\n' => {
+                serial_println!();
+                pending.clear();
+                let out = with_stdout(Stdout::Serial, || eval(line.as_str(), &env));
+                serial_println!(">>:{}", out);
+                line.clear();
+                serial_print!("[IN]:");
+            }
+            // 删除和回退, 一次删掉一个完整的字符
+            0x08 | 0x7f => {
+                pending.clear();
+                if line.pop().is_some() {
+                    serial_print!("\u{8} \u{8}");
+                }
+            }
+            // Ctrl+C 清空输入
+            0x03 => {
+                line.clear();
+                pending.clear();
+                serial_println!("^C");
+                serial_print!("[IN]:");
+            }
+            _ if byte < 0x20 => {}
+            _ => {
+                pending.push(byte);
+                match core::str::from_utf8(&pending) {
+                    Ok(text) => {
+                        line.push_str(text);
+                        serial_print!("{}", text);
+                        pending.clear();
+                    }
+                    // 多字节字符还没收完
+                    Err(err) if err.error_len().is_none() => {}
+                    // 不是合法的 UTF-8, 丢掉; 打断它的 ASCII 字符还要保留
+                    Err(_) => {
+                        pending.clear();
+                        if byte.is_ascii() {
+                            line.push(byte as char);
+                            serial_print!("{}", byte as char);
                         }
-                    },
+                    }
                 }
             }
         }
+        last = byte;
     }
 }
 
@@ -118,6 +225,9 @@ pub fn head() {
     );
     println!("Weclome to my page:https://github.com/zzhgithub/juner_os");
     println!("you can use MAL a small lisp!");
+    if let Some(now) = crate::time::rtc::now() {
+        println!("session started at {} UTC", now);
+    }
     println!();
     // let mut i = 0;
     // loop {
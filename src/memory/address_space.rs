@@ -0,0 +1,276 @@
+//! Separate address spaces.
+//!
+//! Every address space has its own level 4 table. The kernel's entries are
+//! shared by copying the level 4 entries, so all address spaces see the same
+//! level 3 tables below them: the whole upper half, where the region allocator
+//! puts kernel memory, and the lower half entries the bootloader had already
+//! populated when the kernel took over (kernel image, physical memory mapping).
+//! All remaining lower half entries belong to the address space alone.
+
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        page_table::PageTableEntry, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable,
+        Page, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{phys_to_virt, vmm, BitmapFrameAllocator, FRAME_ALLOCATOR};
+use crate::sync::Mutex;
+
+#[derive(Clone, Copy)]
+struct KernelEntries {
+    level_4_frame: Option<PhysFrame>,
+    shared: [bool; 512],
+}
+
+static KERNEL: Mutex<KernelEntries> = Mutex::new(KernelEntries {
+    level_4_frame: None,
+    shared: [false; 512],
+});
+
+/// Records which level 4 entries of the active table belong to the kernel and
+/// creates the level 3 tables of the region allocator's range up front, so
+/// later kernel mappings show up in every address space.
+pub(super) fn init_kernel_entries(frame_allocator: &mut BitmapFrameAllocator) {
+    let (level_4_frame, _) = Cr3::read();
+    let table = unsafe { table_mut(level_4_frame) };
+
+    let first = VirtAddr::new(vmm::KERNEL_VIRT_START).p4_index();
+    let last = VirtAddr::new(vmm::KERNEL_VIRT_END - 1).p4_index();
+    for index in usize::from(first)..=usize::from(last) {
+        if table[index].is_unused() {
+            let frame = frame_allocator
+                .allocate_frame()
+                .expect("no frame for kernel level 3 table");
+            unsafe { table_mut(frame) }.zero();
+            table[index].set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+
+    let mut kernel = KERNEL.lock();
+    kernel.level_4_frame = Some(level_4_frame);
+    for (index, entry) in table.iter().enumerate() {
+        kernel.shared[index] = index >= 256 || !entry.is_unused();
+    }
+}
+
+/// Returns whether level 4 entry `index` belongs to the kernel.
+pub fn is_kernel_entry(index: usize) -> bool {
+    KERNEL.lock().shared[index]
+}
+
+/// Switches back to the kernel's own address space.
+pub fn switch_to_kernel() {
+    let frame = KERNEL.lock().level_4_frame.expect("memory not initialized");
+    let (current, flags) = Cr3::read();
+    if current != frame {
+        unsafe { Cr3::write(frame, flags) };
+    }
+}
+
+/// Translates `addr` in the address space that is loaded in CR3. Also returns
+/// the flags that are set on every level of the walk.
+pub fn translate_active(addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+    let mut table = unsafe { table_mut(Cr3::read().0) };
+    let mut flags = PageTableFlags::all();
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    for (level, &index) in indexes.iter().enumerate() {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        flags &= entry.flags();
+        // 1 GiB 和 2 MiB 的大页在第 3 和第 2 级
+        let huge = (level == 1 || level == 2) && entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        if level == 3 || huge {
+            let page_size = 1u64 << (12 + 9 * (3 - level));
+            return Some((entry.addr() + (addr.as_u64() & (page_size - 1)), flags));
+        }
+        table = unsafe { table_mut(PhysFrame::containing_address(entry.addr())) };
+    }
+    unreachable!()
+}
+
+/// Returns whether `page` is mapped user accessible in the address space that
+/// is loaded in CR3.
+pub fn is_user_mapped(page: Page) -> bool {
+    translate_active(page.start_address()).map_or(false, |(_, flags)| {
+        flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpaceError {
+    /// `memory::install` has not been called yet.
+    NotInitialized,
+    /// No frame for the level 4 table was available.
+    OutOfMemory,
+}
+
+/// A level 4 page table that shares the kernel's mappings. Dropping it frees
+/// every frame mapped in its own part of the lower half, including the page
+/// tables.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space that contains only the kernel mappings.
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        let kernel = *KERNEL.lock();
+        let kernel_frame = kernel
+            .level_4_frame
+            .ok_or(AddressSpaceError::NotInitialized)?;
+        let frame = with_frame_allocator(|a| a.allocate_frame())
+            .ok_or(AddressSpaceError::NotInitialized)?
+            .ok_or(AddressSpaceError::OutOfMemory)?;
+
+        let table = unsafe { table_mut(frame) };
+        let kernel_table = unsafe { table_mut(kernel_frame) };
+        table.zero();
+        for index in 0..512 {
+            if kernel.shared[index] {
+                table[index] = kernel_table[index].clone();
+            }
+        }
+        Ok(AddressSpace {
+            level_4_frame: frame,
+        })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns whether this address space is loaded in CR3.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Loads this address space into CR3.
+    ///
+    /// This function is unsafe because the code and stack the caller runs on
+    /// must be mapped in the new address space, which holds for everything the
+    /// kernel allocated, but not for the own part of another address space.
+    pub unsafe fn switch(&self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+
+    /// Returns a mapper for this address space. Page tables it needs are taken
+    /// from the global frame allocator passed to `Mapper::map_to`.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let table = unsafe { table_mut(self.level_4_frame) };
+        unsafe { OffsetPageTable::new(table, phys_to_virt(PhysAddr::new(0))) }
+    }
+
+    /// Maps `page` to a fresh zeroed frame in this address space.
+    pub fn map_new(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, AddressSpaceError> {
+        assert!(
+            !is_kernel_entry(usize::from(page.p4_index())),
+            "{:?} lies in the kernel's part of the address space",
+            page
+        );
+        let level_4_frame = self.level_4_frame;
+        let mut mapper = self.mapper();
+        with_frame_allocator(|frame_allocator| {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(AddressSpaceError::OutOfMemory)?;
+            let frame_ptr: *mut u8 = phys_to_virt(frame.start_address()).as_mut_ptr();
+            unsafe { core::ptr::write_bytes(frame_ptr, 0, 4096) };
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                        unsafe { allow_user_access(level_4_frame, page) };
+                    }
+                    Ok(frame)
+                }
+                Err(_) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    Err(AddressSpaceError::OutOfMemory)
+                }
+            }
+        })
+        .unwrap_or(Err(AddressSpaceError::NotInitialized))
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if self.is_active() {
+            switch_to_kernel();
+        }
+        let table = unsafe { table_mut(self.level_4_frame) };
+        let level_4_frame = self.level_4_frame;
+        with_frame_allocator(|frame_allocator| {
+            for index in 0..256 {
+                if !is_kernel_entry(index) {
+                    unsafe { free_table(&mut table[index], 4, frame_allocator) };
+                }
+            }
+            unsafe { frame_allocator.deallocate_frame(level_4_frame) };
+        });
+    }
+}
+
+/// Sets `USER_ACCESSIBLE` on the table entries leading to `page`, which the
+/// mapper creates without it. The CPU checks the flag on every level.
+unsafe fn allow_user_access(level_4_frame: PhysFrame, page: Page) {
+    let indexes = [page.p4_index(), page.p3_index(), page.p2_index()];
+    let mut table = table_mut(level_4_frame);
+    for &index in indexes.iter() {
+        let entry = &mut table[index];
+        entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+        table = table_mut(PhysFrame::containing_address(entry.addr()));
+    }
+}
+
+/// Frees the frames below `entry`, which lives in a table of the given level,
+/// and clears it.
+unsafe fn free_table(
+    entry: &mut PageTableEntry,
+    level: u8,
+    frame_allocator: &mut BitmapFrameAllocator,
+) {
+    if entry.is_unused() {
+        return;
+    }
+    let huge = entry.flags().contains(PageTableFlags::HUGE_PAGE);
+    match (level, huge) {
+        (3, true) => frame_allocator
+            .deallocate_frame(PhysFrame::<Size1GiB>::containing_address(entry.addr())),
+        (2, true) => frame_allocator
+            .deallocate_frame(PhysFrame::<Size2MiB>::containing_address(entry.addr())),
+        (1, _) => frame_allocator
+            .deallocate_frame(PhysFrame::<Size4KiB>::containing_address(entry.addr())),
+        _ => {
+            let frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+            for child in table_mut(frame).iter_mut() {
+                free_table(child, level - 1, frame_allocator);
+            }
+            frame_allocator.deallocate_frame(frame);
+        }
+    }
+    entry.set_unused();
+}
+
+fn with_frame_allocator<R>(f: impl FnOnce(&mut BitmapFrameAllocator) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| FRAME_ALLOCATOR.lock().as_mut().map(f))
+}
+
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+}
@@ -0,0 +1,228 @@
+//! Page fault resolution.
+//!
+//! Virtual ranges can be registered as lazily backed, in which case the first
+//! access to a page maps a zeroed frame, or as guard pages, in which case any
+//! access is reported as a stack overflow of the named stack.
+
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
+        },
+    },
+    VirtAddr,
+};
+
+use super::address_space;
+use crate::sync::Mutex;
+
+const MAX_FAULT_REGIONS: usize = 64;
+
+/// How many pages below the stack pointer `register_current_stack_guard`
+/// looks for the guard page.
+const MAX_STACK_PAGES: u64 = 4096;
+
+/// How far below the interrupted stack pointer an access is still considered
+/// to be a stack overflow of an unregistered stack.
+const STACK_OVERFLOW_SLACK: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Pages are mapped with the given flags on first access.
+    Lazy(PageTableFlags),
+    /// Pages must never be mapped; an access overflowed the named stack.
+    Guard(&'static str),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FaultRegion {
+    start: VirtAddr,
+    end: VirtAddr,
+    kind: RegionKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// The range overlaps an already registered region.
+    Overlap,
+    /// All region slots are in use.
+    TooManyRegions,
+    /// No unmapped page was found below the stack.
+    NoGuardPage,
+}
+
+/// The outcome of `handle_page_fault`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultResolution {
+    /// The page was mapped and the faulting instruction can be retried.
+    Resolved,
+    /// The access hit a guard page or ran just below the stack pointer.
+    StackOverflow(&'static str),
+    /// Nothing can be done about this fault.
+    Unrecoverable,
+}
+
+// 用定长数组保存, 处理缺页时不能再去堆上分配内存
+static REGIONS: Mutex<[Option<FaultRegion>; MAX_FAULT_REGIONS]> =
+    Mutex::new([None; MAX_FAULT_REGIONS]);
+
+fn register(start: VirtAddr, size: u64, kind: RegionKind) -> Result<(), RegionError> {
+    let end = start + size;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        if regions
+            .iter()
+            .flatten()
+            .any(|r| start < r.end && r.start < end)
+        {
+            return Err(RegionError::Overlap);
+        }
+        let slot = regions
+            .iter_mut()
+            .find(|r| r.is_none())
+            .ok_or(RegionError::TooManyRegions)?;
+        *slot = Some(FaultRegion { start, end, kind });
+        Ok(())
+    })
+}
+
+/// Registers `size` bytes at `start` to be backed by zeroed frames on first access.
+pub fn register_lazy(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), RegionError> {
+    register(
+        start,
+        size,
+        RegionKind::Lazy(flags | PageTableFlags::PRESENT),
+    )
+}
+
+/// Registers `size` bytes at `start` as the guard area below the stack `name`.
+pub fn register_guard(start: VirtAddr, size: u64, name: &'static str) -> Result<(), RegionError> {
+    register(start, size, RegionKind::Guard(name))
+}
+
+/// Registers the unmapped page below the stack the caller runs on as the guard
+/// of the stack `name` and returns its address.
+///
+/// This is for stacks that were not allocated with `vmm::allocate_stack`,
+/// like the boot stack, below which the bootloader leaves a page unmapped.
+pub fn register_current_stack_guard(name: &'static str) -> Result<VirtAddr, RegionError> {
+    let rsp: u64;
+    unsafe { llvm_asm!("mov %rsp, $0" : "=r"(rsp)) };
+    let mut page = Page::<Size4KiB>::containing_address(VirtAddr::new(rsp));
+    for _ in 0..MAX_STACK_PAGES {
+        let below = page - 1;
+        if address_space::translate_active(below.start_address()).is_none() {
+            register_guard(below.start_address(), Size4KiB::SIZE, name)?;
+            return Ok(below.start_address());
+        }
+        page = below;
+    }
+    Err(RegionError::NoGuardPage)
+}
+
+/// Removes the region starting at `start`. Pages that were already mapped in a
+/// lazy region stay mapped and must be unmapped by the owner.
+pub fn unregister(start: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for region in REGIONS.lock().iter_mut() {
+            if region.map_or(false, |r| r.start == start) {
+                *region = None;
+            }
+        }
+    });
+}
+
+fn find_region(addr: VirtAddr) -> Option<FaultRegion> {
+    // 这个处理器上被中断的代码可能正拿着这个锁, 这时只能当作无法恢复;
+    // 别的处理器拿着就等它放开
+    let regions = REGIONS.lock_unless_held_here()?;
+    regions
+        .iter()
+        .flatten()
+        .find(|r| r.start <= addr && addr < r.end)
+        .cloned()
+}
+
+/// Tries to resolve a page fault at `addr`.
+///
+/// `stack_pointer` is the stack pointer of the interrupted code and is used to
+/// recognize overflows of stacks that have no registered guard region.
+pub fn handle_page_fault(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+    stack_pointer: VirtAddr,
+) -> FaultResolution {
+    let region = find_region(addr);
+    match region.map(|r| r.kind) {
+        Some(RegionKind::Guard(name)) => return FaultResolution::StackOverflow(name),
+        Some(RegionKind::Lazy(flags))
+            if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) =>
+        {
+            if map_zeroed_page(Page::containing_address(addr), flags) {
+                return FaultResolution::Resolved;
+            }
+        }
+        _ => {}
+    }
+
+    let sp = stack_pointer.as_u64();
+    if region.is_none()
+        && !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && addr.as_u64() < sp
+        && sp - addr.as_u64() <= STACK_OVERFLOW_SLACK
+    {
+        return FaultResolution::StackOverflow("unknown stack");
+    }
+    FaultResolution::Unrecoverable
+}
+
+/// Returns the name of the stack that an access at `addr` overflowed, or
+/// `None` if the access was not a stack overflow.
+///
+/// The page fault handler runs on the stack of the interrupted code, so when
+/// that stack overflows it cannot even be entered and the processor raises a
+/// double fault instead. The double fault handler calls this with CR2 and the
+/// interrupted stack pointer to tell that case apart.
+pub fn overflowed_stack(addr: VirtAddr, stack_pointer: VirtAddr) -> Option<&'static str> {
+    match find_region(addr).map(|r| r.kind) {
+        Some(RegionKind::Guard(name)) => Some(name),
+        Some(RegionKind::Lazy(_)) => None,
+        None => {
+            let sp = stack_pointer.as_u64();
+            if addr.as_u64() < sp
+                && sp - addr.as_u64() <= STACK_OVERFLOW_SLACK
+                && address_space::translate_active(addr).is_none()
+            {
+                Some("unknown stack")
+            } else {
+                None
+            }
+        }
+    }
+}
+
+/// Maps a zeroed frame at `page`, returning `false` if no frame is available or
+/// the mapper is held by the interrupted code.
+fn map_zeroed_page(page: Page, flags: PageTableFlags) -> bool {
+    super::try_with_mapper(|mapper, frame_allocator| {
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        let frame_ptr: *mut u8 = super::phys_to_virt(frame.start_address()).as_mut_ptr();
+        unsafe { core::ptr::write_bytes(frame_ptr, 0, Size4KiB::SIZE as usize) };
+
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                false
+            }
+        }
+    })
+    .unwrap_or(false)
+}
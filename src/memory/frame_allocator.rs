@@ -0,0 +1,257 @@
+use core::slice;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB,
+        Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+
+/// A physical frame allocator backed by a bitmap with one bit per 4 KiB frame.
+///
+/// The bitmap itself is stored in the first usable region that is large
+/// enough to hold it and is accessed through the bootloader's physical memory
+/// mapping, so the allocator works before the kernel heap exists and never
+/// allocates from the heap itself.
+///
+/// A set bit means the frame is in use (or not usable at all). Single frame
+/// allocations scan whole 64-bit words starting at a hint that always points
+/// at or before the first word with a free frame, which keeps allocation
+/// amortized O(1).
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+    total_frames: usize,
+    free_frames: usize,
+    next_word: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a frame allocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid, that all frames that are marked as `USABLE` in it are
+    /// really unused and that the complete physical memory is mapped at
+    /// `physical_memory_offset`.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // the bitmap only needs to cover memory up to the end of the last usable region
+        let max_addr = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .unwrap_or(0);
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let word_count = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_bytes = (word_count * 8) as u64;
+        let bitmap_frames = (bitmap_bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        // place the bitmap at the start of the first usable region that can hold it
+        let bitmap_start = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_frames * FRAME_SIZE)
+            .map(|r| r.range.start_addr())
+            .expect("no usable region large enough for the frame bitmap");
+
+        let bitmap_ptr = (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, word_count);
+
+        // everything is in use until the memory map says otherwise
+        for word in bitmap.iter_mut() {
+            *word = u64::max_value();
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            frame_count,
+            total_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+        };
+
+        for region in usable_regions() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for index in start..end {
+                allocator.clear_bit(index);
+            }
+            allocator.total_frames += end - start;
+        }
+        allocator.free_frames = allocator.total_frames;
+
+        // the frames holding the bitmap are never handed out
+        let first_bitmap_frame = (bitmap_start / FRAME_SIZE) as usize;
+        for index in first_bitmap_frame..first_bitmap_frame + bitmap_frames as usize {
+            allocator.set_bit(index);
+        }
+        allocator.free_frames -= bitmap_frames as usize;
+
+        allocator
+    }
+
+    /// Returns the number of usable frames reported by the memory map.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Returns the number of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the number of usable frames that are currently allocated.
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Allocates `count` physically contiguous frames whose first frame is aligned
+    /// to `align` frames, e.g. for DMA buffers.
+    ///
+    /// Returns `None` if there is no free run of the requested size.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        if count == 0 {
+            return None;
+        }
+        let align = align.max(1);
+        let mut start = self.next_word * BITS_PER_WORD;
+        start = (start + align - 1) / align * align;
+
+        while start + count <= self.frame_count {
+            match (start..start + count).find(|&index| self.is_used(index)) {
+                // restart the search behind the first used frame of this window
+                Some(used) => start = (used + 1 + align - 1) / align * align,
+                None => {
+                    for index in start..start + count {
+                        self.set_bit(index);
+                    }
+                    self.free_frames -= count;
+                    self.update_hint();
+                    let first = frame_at(start);
+                    return Some(PhysFrame::range(first, first + count as u64));
+                }
+            }
+        }
+        None
+    }
+
+    /// Frees a range of frames that was returned by `allocate_contiguous`.
+    ///
+    /// This function is unsafe because the caller must guarantee that the frames
+    /// are no longer mapped or otherwise in use.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    /// Allocates a single frame that lies below `limit`, for code that runs
+    /// before paging is enabled, like the start-up code of other processors.
+    pub fn allocate_frame_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        let end = ((limit.as_u64() / FRAME_SIZE) as usize).min(self.frame_count);
+        let index = (0..end).find(|&index| !self.is_used(index))?;
+        self.set_bit(index);
+        self.free_frames -= 1;
+        self.update_hint();
+        Some(frame_at(index))
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    /// Moves the search hint forward to the first word that still has a free frame.
+    fn update_hint(&mut self) {
+        while self.next_word < self.bitmap.len() && self.bitmap[self.next_word] == u64::max_value()
+        {
+            self.next_word += 1;
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.update_hint();
+        let word_index = self.next_word;
+        let word = *self.bitmap.get(word_index)?;
+        let index = word_index * BITS_PER_WORD + word.trailing_ones() as usize;
+        if index >= self.frame_count {
+            return None;
+        }
+        self.set_bit(index);
+        self.free_frames -= 1;
+        Some(frame_at(index))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(
+            index < self.frame_count && self.is_used(index),
+            "deallocating frame {:?} that is not allocated",
+            frame
+        );
+        self.clear_bit(index);
+        self.free_frames += 1;
+        if index / BITS_PER_WORD < self.next_word {
+            self.next_word = index / BITS_PER_WORD;
+        }
+    }
+}
+
+// 大页就是按大页大小对齐的一段连续的 4 KiB 页帧
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        allocate_huge(self)
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        allocate_huge(self)
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        deallocate_huge(self, frame)
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        deallocate_huge(self, frame)
+    }
+}
+
+fn allocate_huge<S: PageSize>(allocator: &mut BitmapFrameAllocator) -> Option<PhysFrame<S>> {
+    let count = (S::SIZE / FRAME_SIZE) as usize;
+    let range = allocator.allocate_contiguous(count, count)?;
+    Some(PhysFrame::containing_address(range.start.start_address()))
+}
+
+unsafe fn deallocate_huge<S: PageSize>(allocator: &mut BitmapFrameAllocator, frame: PhysFrame<S>) {
+    let first = PhysFrame::<Size4KiB>::containing_address(frame.start_address());
+    let count = S::SIZE / FRAME_SIZE;
+    allocator.deallocate_contiguous(PhysFrame::range(first, first + count));
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
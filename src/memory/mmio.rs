@@ -0,0 +1,147 @@
+//! Mapping of device memory for drivers.
+
+use alloc::vec::Vec;
+use x86_64::{
+    structures::paging::{Page, PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    paging::{self, PageKind},
+    vmm::{self, Backing, Region, VmError},
+};
+
+/// How the CPU may cache accesses to a device mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Caching {
+    /// Every access goes to the device, for registers.
+    Uncached,
+    /// Reads may be cached but writes always reach the device, for framebuffers.
+    WriteThrough,
+}
+
+impl Caching {
+    fn flags(self) -> PageTableFlags {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        match self {
+            Caching::Uncached => flags | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+            Caching::WriteThrough => flags | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Maps `len` bytes of device registers at `phys` uncached and returns the
+/// virtual address of `phys`.
+pub fn map_mmio(phys: PhysAddr, len: u64) -> Result<VirtAddr, VmError> {
+    map_device("mmio", phys, len, Caching::Uncached)
+}
+
+/// Maps `len` bytes of device memory at `phys` with the given caching mode and
+/// returns the virtual address of `phys`.
+///
+/// Ranges of at least 2 MiB are mapped with 2 MiB pages where the physical
+/// address allows it, which is what large framebuffers want.
+pub fn map_device(
+    name: &'static str,
+    phys: PhysAddr,
+    len: u64,
+    caching: Caching,
+) -> Result<VirtAddr, VmError> {
+    let offset = phys.as_u64() % Size4KiB::SIZE;
+    let phys_start = phys.align_down(Size4KiB::SIZE);
+    let size = align_up(offset + len, Size4KiB::SIZE);
+    // 虚拟地址和物理地址在 2 MiB 内的偏移一致时才能用大页: 区域按 2 MiB 对齐,
+    // 开头空出物理地址在 2 MiB 内的偏移, 这部分不映射
+    let (align, skew) = if size >= Size2MiB::SIZE {
+        (Size2MiB::SIZE, phys_start.as_u64() % Size2MiB::SIZE)
+    } else {
+        (Size4KiB::SIZE, 0)
+    };
+    let flags = caching.flags();
+    let region_start = vmm::reserve(
+        name,
+        skew + size,
+        align,
+        flags,
+        Backing::Mmio(phys_start - skew),
+    )?;
+    let virt_start = region_start + skew;
+
+    let mut done = 0;
+    while done < size {
+        let virt = virt_start + done;
+        let phys = phys_start + done;
+        let result = if virt.is_aligned(Size2MiB::SIZE)
+            && phys.is_aligned(Size2MiB::SIZE)
+            && size - done >= Size2MiB::SIZE
+        {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            let frame = PhysFrame::<Size2MiB>::containing_address(phys);
+            unsafe { paging::map_to(page, frame, flags) }
+                .map(|_| Size2MiB::SIZE)
+                .map_err(|_| ())
+        } else {
+            let page = Page::<Size4KiB>::containing_address(virt);
+            let frame = PhysFrame::<Size4KiB>::containing_address(phys);
+            unsafe { paging::map_to(page, frame, flags) }
+                .map(|_| Size4KiB::SIZE)
+                .map_err(|_| ())
+        };
+        match result {
+            Ok(step) => done += step,
+            Err(()) => {
+                // release 会把已经映射的部分取消映射
+                let _ = unsafe { vmm::release(region_start) };
+                return Err(VmError::OutOfMemory);
+            }
+        }
+    }
+    Ok(virt_start + offset)
+}
+
+/// Removes a mapping created by `map_mmio` or `map_device`. `virt` may be any
+/// address inside the mapping.
+///
+/// This function is unsafe because the caller must guarantee that the mapping
+/// is not used anymore.
+pub unsafe fn unmap_mmio(virt: VirtAddr) -> Result<(), VmError> {
+    match vmm::find(virt) {
+        Some(region) if is_mmio(&region) => vmm::release(region.start).map(|_| ()),
+        _ => Err(VmError::NotFound),
+    }
+}
+
+/// Returns all device mappings.
+pub fn mappings() -> Vec<Region> {
+    vmm::regions().into_iter().filter(is_mmio).collect()
+}
+
+fn is_mmio(region: &Region) -> bool {
+    match region.backing {
+        Backing::Mmio(_) => true,
+        _ => false,
+    }
+}
+
+/// Unmaps device pages without returning their frames to the frame allocator.
+pub(super) unsafe fn unmap_pages(start: VirtAddr, size: u64) {
+    let end = start + size;
+    let mut addr = start;
+    while addr < end {
+        addr += match paging::translate(addr) {
+            Some((_, PageKind::Size2MiB)) => {
+                let _ = paging::unmap(Page::<Size2MiB>::containing_address(addr));
+                Size2MiB::SIZE
+            }
+            Some(_) => {
+                let _ = paging::unmap(Page::<Size4KiB>::containing_address(addr));
+                Size4KiB::SIZE
+            }
+            None => Size4KiB::SIZE,
+        };
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,234 @@
+use x86_64::{
+    PhysAddr,
+    VirtAddr,
+    structures::paging::{
+        Page, PhysFrame, Mapper, PageSize, Size4KiB, Size2MiB, Size1GiB, FrameAllocator,
+        PageTable, OffsetPageTable,
+    }
+};
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use log::info;
+
+use crate::sync::Mutex;
+
+pub mod address_space;
+pub mod fault;
+mod frame_allocator;
+pub mod mmio;
+pub mod paging;
+pub mod vmm;
+
+pub use frame_allocator::BitmapFrameAllocator;
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static MEMORY_MAP: OnceCell<&'static MemoryMap> = OnceCell::uninit();
+
+// 锁的顺序: 内核堆, MAPPER, FRAME_ALLOCATOR. 堆增长时拿着堆锁去映射新页,
+// 所以拿着后两个锁的时候不能分配或释放堆内存 (allocator 里会检查)
+
+/// The kernel's active page table, available after `install` has been called.
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// The global physical frame allocator, available after `install` has been called.
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+/// Moves the mapper and frame allocator created at boot into the kernel-wide
+/// statics so that the heap, page fault handler and drivers can use them.
+pub fn install(mapper: OffsetPageTable<'static>, mut frame_allocator: BitmapFrameAllocator) {
+    address_space::init_kernel_entries(&mut frame_allocator);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *MAPPER.lock() = Some(mapper);
+        *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    });
+}
+
+/// Remembers the bootloader's memory map for later inspection and writes a
+/// summary of it to the log.
+pub fn init_memory_map(memory_map: &'static MemoryMap) {
+    MEMORY_MAP
+        .try_init_once(|| memory_map)
+        .expect("init_memory_map should only be called once");
+
+    let mut usable = 0;
+    for region in memory_map.iter() {
+        let start = region.range.start_addr();
+        let end = region.range.end_addr();
+        info!(
+            "memory: {:#012x}-{:#012x} {:>8} KiB {:?}",
+            start,
+            end,
+            (end - start) / 1024,
+            region.region_type
+        );
+        if region.region_type == MemoryRegionType::Usable {
+            usable += end - start;
+        }
+    }
+    info!(
+        "memory: {} regions, {} MiB usable",
+        memory_map.iter().count(),
+        usable / 1024 / 1024
+    );
+}
+
+/// Returns the bootloader's memory map once `init_memory_map` has been called.
+pub fn memory_map() -> Option<&'static MemoryMap> {
+    MEMORY_MAP.try_get().ok().cloned()
+}
+
+/// Like `with_mapper`, but for exception handlers: returns `None` instead of
+/// spinning forever if the code the exception interrupted on this processor
+/// holds the mapper or frame allocator. Other processors are waited for.
+pub fn try_with_mapper<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock_unless_held_here()?;
+        let mut frame_allocator = FRAME_ALLOCATOR.lock_unless_held_here()?;
+        match (mapper.as_mut(), frame_allocator.as_mut()) {
+            (Some(mapper), Some(frame_allocator)) => Some(f(mapper, frame_allocator)),
+            _ => None,
+        }
+    })
+}
+
+/// Returns the virtual address at which the given physical address is
+/// accessible through the bootloader's physical memory mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// Physical frame counts of the global frame allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    pub used: usize,
+}
+
+/// Returns the frame counts, or `None` before `install` has been called.
+pub fn frame_stats() -> Option<FrameStats> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().as_ref().map(|frame_allocator| FrameStats {
+            total: frame_allocator.total_frames(),
+            free: frame_allocator.free_frames(),
+            used: frame_allocator.used_frames(),
+        })
+    })
+}
+
+/// Runs `f` with exclusive access to the kernel mapper and frame allocator.
+///
+/// Returns `None` if `install` has not been called yet. The closure runs with
+/// interrupts disabled and must neither allocate nor free kernel heap memory,
+/// because the heap itself calls this function when it needs to grow.
+pub fn with_mapper<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        match (mapper.as_mut(), frame_allocator.as_mut()) {
+            (Some(mapper), Some(frame_allocator)) => Some(f(mapper, frame_allocator)),
+            _ => None,
+        }
+    })
+}
+
+pub struct EmptyFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        None
+    }
+}
+
+/// Initialize a new OffsetPageTable.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`. Also, this function must be only called once
+/// to avoid aliasing `&mut` references (which is undefined behavior).
+pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
+    -> &'static mut PageTable
+{
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr // unsafe
+}
+
+
+/// Translates the given virtual address to the mapped physical address, or
+/// `None` if the address is not mapped.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`.
+pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
+    -> Option<PhysAddr>
+{
+    translate_addr_inner(addr, physical_memory_offset)
+}
+
+
+// Private function that is called by `translate_addr`.
+///
+/// This function is safe to limit the scope of `unsafe` because Rust treats
+/// the whole body of unsafe functions as an unsafe block. This function must
+/// only be reachable through `unsafe fn` from outside of this module.
+fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
+    -> Option<PhysAddr>
+{
+    use x86_64::structures::paging::page_table::FrameError;
+    use x86_64::registers::control::Cr3;
+
+    // read the active level 4 frame from the CR3 register
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let table_indexes = [
+        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
+    ];
+    let mut frame = level_4_table_frame;
+
+    // traverse the multi-level page table
+    for (level, &index) in table_indexes.iter().enumerate() {
+        // convert the frame into a page table reference
+        let virt = physical_memory_offset + frame.start_address().as_u64();
+        let table_ptr: *const PageTable = virt.as_ptr();
+        let table = unsafe {&*table_ptr};
+
+        // read the page table entry and update `frame`
+        let entry = &table[index];
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return None,
+            Err(FrameError::HugeFrame) => {
+                // a huge page maps the rest of the address directly
+                let offset_mask = match level {
+                    1 => Size1GiB::SIZE - 1,
+                    2 => Size2MiB::SIZE - 1,
+                    _ => return None, // the huge bit is reserved in level 4 and 1 entries
+                };
+                return Some(entry.addr() + (addr.as_u64() & offset_mask));
+            }
+        };
+    }
+
+    // calculate the physical address by adding the page offset
+    Some(frame.start_address() + u64::from(addr.page_offset()))
+}
\ No newline at end of file
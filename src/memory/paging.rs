@@ -0,0 +1,169 @@
+//! Mapping helpers that work with 4 KiB, 2 MiB and 1 GiB pages.
+
+use super::{BitmapFrameAllocator, FRAME_ALLOCATOR, MAPPER};
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MapperAllSizes, TranslateResult, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// The size of the page an address is mapped with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageKind {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+/// Translates `addr` in the kernel page table and also reports the size of the
+/// page it is mapped with.
+pub fn translate(addr: VirtAddr) -> Option<(PhysAddr, PageKind)> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mapper = MAPPER.lock();
+        match mapper.as_ref()?.translate(addr) {
+            TranslateResult::Frame4KiB { frame, offset } => {
+                Some((frame.start_address() + offset, PageKind::Size4KiB))
+            }
+            TranslateResult::Frame2MiB { frame, offset } => {
+                Some((frame.start_address() + offset, PageKind::Size2MiB))
+            }
+            TranslateResult::Frame1GiB { frame, offset } => {
+                Some((frame.start_address() + offset, PageKind::Size1GiB))
+            }
+            TranslateResult::PageNotMapped | TranslateResult::InvalidFrameAddress(_) => None,
+        }
+    })
+}
+
+/// Maps `page` to the given `frame` in the kernel page table.
+///
+/// This function is unsafe because the caller must guarantee that the frame is
+/// not used for anything else, see `Mapper::map_to`.
+pub unsafe fn map_to<S: PageSize>(
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<S>>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    super::with_mapper(|mapper, frame_allocator| {
+        mapper
+            .map_to(page, frame, flags, frame_allocator)
+            .map(|flush| flush.flush())
+    })
+    .unwrap_or(Err(MapToError::FrameAllocationFailed))
+}
+
+/// Maps `page` to a freshly allocated frame of the same size and returns it.
+pub fn map_new<S: PageSize>(
+    page: Page<S>,
+    flags: PageTableFlags,
+) -> Result<PhysFrame<S>, MapToError<S>>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+    BitmapFrameAllocator: FrameAllocator<S> + FrameDeallocator<S>,
+{
+    super::with_mapper(|mapper, frame_allocator| {
+        let frame = FrameAllocator::<S>::allocate_frame(frame_allocator)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(frame)
+            }
+            Err(err) => {
+                unsafe { FrameDeallocator::<S>::deallocate_frame(frame_allocator, frame) };
+                Err(err)
+            }
+        }
+    })
+    .unwrap_or(Err(MapToError::FrameAllocationFailed))
+}
+
+/// Unmaps `page` from the kernel page table and returns the frame it was mapped to.
+pub fn unmap<S: PageSize>(page: Page<S>) -> Result<PhysFrame<S>, UnmapError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    super::with_mapper(|mapper, _| {
+        mapper.unmap(page).map(|(frame, flush)| {
+            flush.flush();
+            frame
+        })
+    })
+    .unwrap_or(Err(UnmapError::PageNotMapped))
+}
+
+/// Unmaps `page` and returns its frame to the frame allocator.
+///
+/// This function is unsafe because the caller must guarantee that the frame was
+/// allocated from the global frame allocator and is not mapped anywhere else.
+pub unsafe fn unmap_and_free<S: PageSize>(page: Page<S>) -> Result<(), UnmapError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+    BitmapFrameAllocator: FrameDeallocator<S>,
+{
+    let frame = unmap(page)?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(frame_allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+            FrameDeallocator::<S>::deallocate_frame(frame_allocator, frame);
+        }
+    });
+    Ok(())
+}
+
+/// Maps `size` bytes of fresh memory at `start`, using 2 MiB pages wherever
+/// the address is 2 MiB aligned and at least 2 MiB remain and `huge` is set.
+///
+/// Falls back to 4 KiB pages when no 2 MiB frame is available. Returns the
+/// number of bytes mapped before the first failure.
+pub fn map_range(start: VirtAddr, size: u64, flags: PageTableFlags, huge: bool) -> u64 {
+    let end = start + size;
+    let mut addr = start;
+    while addr < end {
+        if huge && addr.is_aligned(Size2MiB::SIZE) && end - addr >= Size2MiB::SIZE {
+            let page = Page::<Size2MiB>::containing_address(addr);
+            if map_new(page, flags).is_ok() {
+                addr += Size2MiB::SIZE;
+                continue;
+            }
+        }
+        let page = Page::<Size4KiB>::containing_address(addr);
+        if map_new(page, flags).is_err() {
+            break;
+        }
+        addr += Size4KiB::SIZE;
+    }
+    addr - start
+}
+
+/// Unmaps a range mapped with `map_range` and frees its frames.
+///
+/// This function is unsafe because the caller must guarantee that nothing uses
+/// the range anymore.
+pub unsafe fn unmap_range(start: VirtAddr, size: u64) {
+    let end = start + size;
+    let mut addr = start;
+    while addr < end {
+        let step = match translate(addr) {
+            Some((_, PageKind::Size1GiB)) => {
+                let _ = unmap_and_free(Page::<Size1GiB>::containing_address(addr));
+                Size1GiB::SIZE
+            }
+            Some((_, PageKind::Size2MiB)) => {
+                let _ = unmap_and_free(Page::<Size2MiB>::containing_address(addr));
+                Size2MiB::SIZE
+            }
+            Some((_, PageKind::Size4KiB)) => {
+                let _ = unmap_and_free(Page::<Size4KiB>::containing_address(addr));
+                Size4KiB::SIZE
+            }
+            None => Size4KiB::SIZE,
+        };
+        addr += step;
+    }
+}
@@ -0,0 +1,229 @@
+//! Kernel virtual address space manager.
+//!
+//! Hands out non-overlapping ranges of the kernel's part of the upper half of
+//! the address space and keeps a list of them, so no module needs to pick a
+//! virtual address by hand. Every region is followed by at least one unmapped
+//! page, so running off its end faults instead of corrupting the neighbour.
+
+use alloc::vec::Vec;
+use x86_64::{structures::paging::PageTableFlags, PhysAddr, VirtAddr};
+
+use super::{fault, mmio, paging};
+use crate::sync::Mutex;
+
+/// Start of the range managed by the region allocator.
+pub const KERNEL_VIRT_START: u64 = 0xffff_c000_0000_0000;
+/// End (exclusive) of the range managed by the region allocator, 8 TiB later.
+pub const KERNEL_VIRT_END: u64 = 0xffff_c800_0000_0000;
+
+const PAGE_SIZE: u64 = 4096;
+const MAX_REGIONS: usize = 128;
+
+/// How the pages of a region are backed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// All pages were mapped to fresh frames when the region was allocated.
+    Eager,
+    /// Pages are mapped to fresh frames by the page fault handler on first access.
+    Lazy,
+    /// A stack whose lowest page is an unmapped guard page.
+    Stack,
+    /// The owner maps pages itself, like the heap does when it grows.
+    Manual,
+    /// Device memory starting at the given physical address; its frames are
+    /// never returned to the frame allocator.
+    Mmio(PhysAddr),
+}
+
+/// A range of kernel virtual memory handed out by the region allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+    pub backing: Backing,
+}
+
+impl Region {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// No free virtual range of the requested size is left.
+    OutOfVirtualSpace,
+    /// All region slots are in use.
+    TooManyRegions,
+    /// Not enough physical memory to back the region.
+    OutOfMemory,
+    /// No region starts at the given address.
+    NotFound,
+}
+
+// 用定长数组保存, 这样在堆初始化之前也能分配区域
+static REGIONS: Mutex<[Option<Region>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+/// Reserves a free virtual range of `size` bytes aligned to `align` without
+/// mapping anything and records it as a region.
+pub fn reserve(
+    name: &'static str,
+    size: u64,
+    align: u64,
+    flags: PageTableFlags,
+    backing: Backing,
+) -> Result<VirtAddr, VmError> {
+    let size = align_up(size, PAGE_SIZE);
+    let align = align.max(PAGE_SIZE);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+
+        // 按起始地址排序之后找第一个放得下的空隙
+        let mut used = [(0u64, 0u64); MAX_REGIONS];
+        let mut count = 0;
+        for region in regions.iter().flatten() {
+            used[count] = (region.start.as_u64(), region.end().as_u64());
+            count += 1;
+        }
+        let used = &mut used[..count];
+        used.sort_unstable();
+
+        let mut candidate = align_up(KERNEL_VIRT_START, align);
+        for &(start, end) in used.iter() {
+            if candidate + size + PAGE_SIZE <= start {
+                break;
+            }
+            candidate = candidate.max(align_up(end + PAGE_SIZE, align));
+        }
+        if candidate + size > KERNEL_VIRT_END {
+            return Err(VmError::OutOfVirtualSpace);
+        }
+
+        let slot = regions
+            .iter_mut()
+            .find(|r| r.is_none())
+            .ok_or(VmError::TooManyRegions)?;
+        let start = VirtAddr::new(candidate);
+        *slot = Some(Region {
+            name,
+            start,
+            size,
+            flags,
+            backing,
+        });
+        Ok(start)
+    })
+}
+
+/// Allocates a region of `size` bytes and maps all of it to fresh frames.
+pub fn allocate(name: &'static str, size: u64, flags: PageTableFlags) -> Result<VirtAddr, VmError> {
+    let start = reserve(name, size, PAGE_SIZE, flags, Backing::Eager)?;
+    let size = align_up(size, PAGE_SIZE);
+    let flags = flags | PageTableFlags::PRESENT;
+    let mapped = paging::map_range(start, size, flags, false);
+    if mapped < size {
+        unsafe { paging::unmap_range(start, mapped) };
+        forget(start);
+        return Err(VmError::OutOfMemory);
+    }
+    Ok(start)
+}
+
+/// Allocates a region of `size` bytes whose pages are mapped on first access.
+pub fn allocate_lazy(
+    name: &'static str,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<VirtAddr, VmError> {
+    let start = reserve(name, size, PAGE_SIZE, flags, Backing::Lazy)?;
+    if fault::register_lazy(start, align_up(size, PAGE_SIZE), flags).is_err() {
+        forget(start);
+        return Err(VmError::TooManyRegions);
+    }
+    Ok(start)
+}
+
+/// Allocates a stack of `pages` mapped pages with an unmapped guard page below
+/// it and returns the top of the stack.
+pub fn allocate_stack(name: &'static str, pages: u64) -> Result<VirtAddr, VmError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let size = (pages + 1) * PAGE_SIZE;
+    let guard = reserve(name, size, PAGE_SIZE, flags, Backing::Stack)?;
+    let bottom = guard + PAGE_SIZE;
+    let mapped = paging::map_range(bottom, pages * PAGE_SIZE, flags, false);
+    if mapped < pages * PAGE_SIZE {
+        unsafe { paging::unmap_range(bottom, mapped) };
+        forget(guard);
+        return Err(VmError::OutOfMemory);
+    }
+    if fault::register_guard(guard, PAGE_SIZE, name).is_err() {
+        unsafe { paging::unmap_range(bottom, mapped) };
+        forget(guard);
+        return Err(VmError::TooManyRegions);
+    }
+    Ok(guard + size)
+}
+
+/// Unmaps the region starting at `start`, returns its frames to the frame
+/// allocator (except for device memory) and forgets it.
+///
+/// This function is unsafe because the caller must guarantee that nothing uses
+/// the region anymore.
+pub unsafe fn release(start: VirtAddr) -> Result<Region, VmError> {
+    let region = find(start)
+        .filter(|r| r.start == start)
+        .ok_or(VmError::NotFound)?;
+    match region.backing {
+        Backing::Mmio(_) => mmio::unmap_pages(region.start, region.size),
+        Backing::Lazy | Backing::Stack => {
+            fault::unregister(region.start);
+            paging::unmap_range(region.start, region.size);
+        }
+        Backing::Eager | Backing::Manual => paging::unmap_range(region.start, region.size),
+    }
+    forget(start);
+    Ok(region)
+}
+
+/// Removes the region starting at `start` from the list without touching its pages.
+fn forget(start: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for region in REGIONS.lock().iter_mut() {
+            if region.map_or(false, |r| r.start == start) {
+                *region = None;
+            }
+        }
+    });
+}
+
+/// Returns the region containing `addr`, if any.
+pub fn find(addr: VirtAddr) -> Option<Region> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        REGIONS
+            .lock()
+            .iter()
+            .flatten()
+            .find(|r| r.contains(addr))
+            .cloned()
+    })
+}
+
+/// Returns all regions sorted by start address.
+pub fn regions() -> Vec<Region> {
+    // 先拷贝出来再分配 Vec, 持有锁的时候不能动堆
+    let snapshot = x86_64::instructions::interrupts::without_interrupts(|| *REGIONS.lock());
+    let mut regions: Vec<Region> = snapshot.iter().flatten().cloned().collect();
+    regions.sort_unstable_by_key(|r| r.start);
+    regions
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
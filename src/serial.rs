@@ -1,25 +1,67 @@
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
+use crate::sync::Mutex;
+
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+/// I/O base of the first serial port.
+pub const COM1: u16 = 0x3f8;
+/// The IRQ line of the first serial port.
+pub const COM1_IRQ: u8 = 4;
+/// I/O base of the second serial port.
+pub const COM2: u16 = 0x2f8;
+
+// 相对于串口基地址的寄存器
+const INTERRUPT_ENABLE: u16 = 1;
+const LINE_STATUS: u16 = 5;
+const RECEIVED_DATA_AVAILABLE: u8 = 1;
+const DATA_READY: u8 = 1;
+
+/// Makes COM1 raise IRQ 4 whenever a byte was received.
+pub fn enable_receive_interrupt() {
+    use x86_64::instructions::interrupts;
+    use x86_64::instructions::port::Port;
+
+    interrupts::without_interrupts(|| {
+        // 持有锁, 顺便保证串口已经初始化
+        let _serial = SERIAL1.lock();
+        unsafe { Port::<u8>::new(COM1 + INTERRUPT_ENABLE).write(RECEIVED_DATA_AVAILABLE) };
+    });
+}
+
+/// Takes a received byte from COM1, if there is one.
+pub fn receive() -> Option<u8> {
+    receive_from(COM1)
+}
+
+/// Takes a received byte from the serial port at `base`, if there is one.
+pub fn receive_from(base: u16) -> Option<u8> {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        if Port::<u8>::new(base + LINE_STATUS).read() & DATA_READY == 0 {
+            return None;
+        }
+        Some(Port::<u8>::new(base).read())
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
+        if let Some(mut serial) = SERIAL1.lock_for_output() {
+            serial.write_fmt(args).expect("Printing to serial failed");
+        }
     });
 }
 
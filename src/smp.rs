@@ -0,0 +1,369 @@
+//! Start-up of the application processors.
+//!
+//! The processors listed in the MADT are started one after another with the
+//! INIT-SIPI-SIPI sequence. They begin in real mode at a trampoline that is
+//! copied to a frame below 1 MiB, switch straight to long mode with the
+//! kernel's page table and call `ap_main` on a stack of their own.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use log::*;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::acpi;
+use crate::interrupts::apic;
+use crate::memory::{paging, phys_to_virt, vmm};
+
+/// The most processors the kernel will use.
+pub const MAX_CPUS: usize = 16;
+
+const AP_STACK_PAGES: u64 = 16;
+
+// 本地 APIC 中断命令寄存器里的启动命令
+const IPI_INIT: u32 = 0x4500;
+const IPI_STARTUP: u32 = 0x4600;
+
+global_asm!(
+    r#"
+    .global ap_trampoline_start
+    .global ap_trampoline_end
+    .global ap_trampoline_long_mode
+    .global ap_trampoline_gdt
+    .global ap_trampoline_gdtr
+    .global ap_trampoline_long_jump
+    .global ap_trampoline_cr3
+    .global ap_trampoline_stack
+    .global ap_trampoline_entry
+    .global ap_trampoline_cpu
+
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    # PAE and global pages
+    mov $0xa0, %eax
+    mov %eax, %cr4
+    mov ap_trampoline_cr3 - ap_trampoline_start, %eax
+    mov %eax, %cr3
+    # EFER: long mode and no-execute
+    mov $0xc0000080, %ecx
+    rdmsr
+    or $0x900, %eax
+    wrmsr
+    lgdtl ap_trampoline_gdtr - ap_trampoline_start
+    # protection, write protect and paging at once enter long mode directly
+    mov %cr0, %eax
+    or $0x80010001, %eax
+    mov %eax, %cr0
+    ljmpl *ap_trampoline_long_jump - ap_trampoline_start
+
+    .code64
+ap_trampoline_long_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    xor %ax, %ax
+    mov %ax, %fs
+    mov %ax, %gs
+    mov ap_trampoline_stack(%rip), %rsp
+    mov ap_trampoline_cpu(%rip), %rdi
+    mov ap_trampoline_entry(%rip), %rax
+    call *%rax
+1:
+    hlt
+    jmp 1b
+
+    .align 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff
+    .quad 0x00cf92000000ffff
+ap_trampoline_gdtr:
+    .word 23
+    .long 0
+ap_trampoline_long_jump:
+    .long 0
+    .word 8
+    .align 8
+ap_trampoline_cr3:
+    .quad 0
+ap_trampoline_stack:
+    .quad 0
+ap_trampoline_entry:
+    .quad 0
+ap_trampoline_cpu:
+    .quad 0
+ap_trampoline_end:
+"#
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_gdtr: u8;
+    static ap_trampoline_long_jump: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_cpu: u8;
+}
+
+/// Per-processor data.
+pub struct Cpu {
+    apic_id: AtomicU32,
+    online: AtomicBool,
+    /// The number of interrupt and exception handlers running, see
+    /// `interrupts::enter`.
+    pub(crate) interrupt_depth: AtomicUsize,
+    /// The number of `sync::Mutex` locks held.
+    pub(crate) locks_held: AtomicUsize,
+    /// The innermost recovery point armed by `catch_faults`, or 0.
+    pub(crate) recovery_point: AtomicUsize,
+    panicking: AtomicBool,
+}
+
+const NO_CPU: u32 = u32::max_value();
+
+impl Cpu {
+    /// Returns the local APIC id, or `None` for an unused slot.
+    pub fn apic_id(&self) -> Option<u8> {
+        match self.apic_id.load(Ordering::Acquire) {
+            NO_CPU => None,
+            id => Some(id as u8),
+        }
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    /// Returns whether the processor is panicking or halting on a fatal
+    /// exception, and will never return to the code it interrupted.
+    pub fn is_panicking(&self) -> bool {
+        self.panicking.load(Ordering::Acquire)
+    }
+
+    /// Marks the processor as panicking, see `is_panicking`.
+    pub fn set_panicking(&self) {
+        self.panicking.store(true, Ordering::Release);
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_CPU: Cpu = Cpu {
+    apic_id: AtomicU32::new(NO_CPU),
+    online: AtomicBool::new(false),
+    interrupt_depth: AtomicUsize::new(0),
+    locks_held: AtomicUsize::new(0),
+    recovery_point: AtomicUsize::new(0),
+    panicking: AtomicBool::new(false),
+};
+
+// 下标就是处理器编号, 0 是启动处理器
+static CPUS: [Cpu; MAX_CPUS] = [EMPTY_CPU; MAX_CPUS];
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Returns the data of all processors that were found, online or not.
+pub fn cpus() -> &'static [Cpu] {
+    &CPUS[..CPU_COUNT.load(Ordering::Acquire)]
+}
+
+/// Returns the number of processors that are running.
+pub fn online_cpus() -> usize {
+    cpus().iter().filter(|cpu| cpu.is_online()).count()
+}
+
+/// Returns the index of the processor this runs on.
+pub fn current_index() -> usize {
+    let apic_id = u32::from(apic::local_apic_id());
+    cpus()
+        .iter()
+        .position(|cpu| cpu.apic_id.load(Ordering::Acquire) == apic_id)
+        .unwrap_or(0)
+}
+
+/// Returns the data of the processor this runs on.
+pub fn current() -> &'static Cpu {
+    &CPUS[current_index()]
+}
+
+/// Returns the data of the processor with the given index.
+pub fn cpu(index: usize) -> &'static Cpu {
+    &CPUS[index]
+}
+
+/// Starts all application processors listed in the MADT. `trampoline` is a
+/// free frame below 1 MiB for the start-up code. Must be called after the
+/// APIC initialization.
+pub fn init(trampoline: Option<PhysFrame>) {
+    CPUS[0]
+        .apic_id
+        .store(u32::from(apic::local_apic_id()), Ordering::Release);
+    CPUS[0].online.store(true, Ordering::Release);
+
+    let madt = match acpi::madt() {
+        Some(madt) if apic::is_enabled() => madt,
+        _ => {
+            info!("SMP: no APIC, running on one processor");
+            return;
+        }
+    };
+    let trampoline = match trampoline {
+        Some(frame) => frame,
+        None => {
+            warn!("SMP: no low memory for the start-up code");
+            return;
+        }
+    };
+
+    // 启动代码在切换到长模式的时候还在低端地址运行, 所以要恒等映射
+    let page =
+        Page::<Size4KiB>::containing_address(VirtAddr::new(trampoline.start_address().as_u64()));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if unsafe { paging::map_to(page, trampoline, flags) }.is_err() {
+        warn!("SMP: cannot identity map the start-up code");
+        return;
+    }
+    unsafe { install_trampoline(trampoline.start_address()) };
+
+    let bsp = apic::local_apic_id();
+    let mut all_started = true;
+    for processor in madt.processors.iter() {
+        if !processor.usable || processor.apic_id == bsp {
+            continue;
+        }
+        let index = CPU_COUNT.load(Ordering::Acquire);
+        if index == MAX_CPUS {
+            warn!("SMP: ignoring processors beyond {}", MAX_CPUS);
+            break;
+        }
+        let stack = match vmm::allocate_stack("ap stack", AP_STACK_PAGES) {
+            Ok(top) => top,
+            Err(err) => {
+                warn!(
+                    "SMP: no stack for processor {}: {:?}",
+                    processor.apic_id, err
+                );
+                break;
+            }
+        };
+        CPUS[index]
+            .apic_id
+            .store(u32::from(processor.apic_id), Ordering::Release);
+        CPU_COUNT.store(index + 1, Ordering::Release);
+        unsafe {
+            patch(
+                trampoline.start_address(),
+                &ap_trampoline_stack,
+                stack.as_u64(),
+            );
+            patch(trampoline.start_address(), &ap_trampoline_cpu, index as u64);
+        }
+        if !start_processor(processor.apic_id, trampoline.start_address(), index) {
+            // 它可能晚一点才启动, 这时不能再改启动代码里的栈
+            warn!("SMP: processor {} did not start", processor.apic_id);
+            all_started = false;
+            break;
+        }
+    }
+
+    if all_started {
+        // 启动的处理器都已经离开启动代码了
+        let _ = unsafe { paging::unmap_and_free(page) };
+    } else {
+        // 没报到的处理器随时可能开始执行启动代码, 这一页永远不能再用
+        warn!(
+            "SMP: keeping the start-up code at {:?} for a late processor",
+            trampoline.start_address()
+        );
+    }
+    info!(
+        "SMP: {} of {} processors online",
+        online_cpus(),
+        cpus().len()
+    );
+}
+
+/// Copies the start-up code to `base` and fills in the values that are the
+/// same for all processors.
+unsafe fn install_trampoline(base: PhysAddr) {
+    let start = &ap_trampoline_start as *const u8;
+    let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+    core::ptr::copy_nonoverlapping(start, phys_to_virt(base).as_mut_ptr::<u8>(), len);
+
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    assert!(cr3 < 1 << 32, "kernel page table above 4 GiB");
+    let gdt = base.as_u64() + offset(&ap_trampoline_gdt);
+    let long_mode = base.as_u64() + offset(&ap_trampoline_long_mode);
+    patch_u32(base, &ap_trampoline_gdtr, 2, gdt as u32);
+    patch_u32(base, &ap_trampoline_long_jump, 0, long_mode as u32);
+    patch(base, &ap_trampoline_cr3, cr3);
+    patch(base, &ap_trampoline_entry, ap_main as usize as u64);
+}
+
+fn offset(symbol: &u8) -> u64 {
+    symbol as *const u8 as u64 - unsafe { &ap_trampoline_start as *const u8 as u64 }
+}
+
+/// Returns where `symbol` of the trampoline lives in the copy at `base`.
+fn copy_of(base: PhysAddr, symbol: &u8) -> *mut u8 {
+    phys_to_virt(base + offset(symbol)).as_mut_ptr()
+}
+
+unsafe fn patch(base: PhysAddr, symbol: &u8, value: u64) {
+    core::ptr::write_volatile(copy_of(base, symbol) as *mut u64, value);
+}
+
+unsafe fn patch_u32(base: PhysAddr, symbol: &u8, field: usize, value: u32) {
+    core::ptr::write_unaligned(copy_of(base, symbol).add(field) as *mut u32, value);
+}
+
+/// Sends the start-up sequence to one processor and waits for it to report
+/// in. Returns `false` if it did not within 100 ms.
+fn start_processor(apic_id: u8, trampoline: PhysAddr, index: usize) -> bool {
+    use crate::time::busy_wait_us;
+
+    let vector = (trampoline.as_u64() >> 12) as u32;
+    apic::send_ipi(apic_id, IPI_INIT);
+    busy_wait_us(10_000);
+    for _ in 0..2 {
+        apic::send_ipi(apic_id, IPI_STARTUP | vector);
+        busy_wait_us(200);
+        if CPUS[index].is_online() {
+            return true;
+        }
+    }
+    for _ in 0..100 {
+        if CPUS[index].is_online() {
+            return true;
+        }
+        busy_wait_us(1000);
+    }
+    false
+}
+
+/// Entry point of the application processors, called by the trampoline.
+extern "C" fn ap_main(index: u64) -> ! {
+    let index = index as usize;
+    crate::gdt::init_percpu();
+    crate::interrupts::init_idt();
+    apic::init_ap();
+    CPUS[index].online.store(true, Ordering::Release);
+    x86_64::instructions::interrupts::enable();
+    idle()
+}
+
+/// Idle loop of the processors that have nothing to do.
+fn idle() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -1,8 +1,10 @@
 use crate::print;
 use crate::println;
+use crate::sync::Mutex;
 use alloc::{collections::vec_deque::VecDeque, string::String, sync::Arc};
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
 use lazy_static::lazy_static;
-use spin::Mutex;
 
 #[derive(Default)]
 pub struct Stdin {
@@ -38,6 +40,12 @@ impl Stdin {
         buf_lock.len()
     }
 
+    // 最后一个输入的字符
+    pub fn last(&self) -> Option<char> {
+        let buf_lock = self.buf.lock();
+        buf_lock.back().cloned()
+    }
+
     // 清空输入缓存
     pub fn clear(&self) {
         let mut buf_lock = self.buf.lock();
@@ -61,3 +69,45 @@ impl Stdin {
 lazy_static! {
     pub static ref STDIN: Arc<Stdin> = Arc::new(Stdin::default());
 }
+
+/// Where the output of mal code goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Stdout {
+    Screen,
+    /// The first serial port, COM1.
+    Serial,
+}
+
+// 求值不会让出执行器, 所以在一次求值期间设置就相当于每个会话一个
+static STDOUT: AtomicU8 = AtomicU8::new(Stdout::Screen as u8);
+
+/// Runs `f` with `stdout_print!` going to `stdout`.
+pub fn with_stdout<R>(stdout: Stdout, f: impl FnOnce() -> R) -> R {
+    let previous = STDOUT.swap(stdout as u8, Ordering::Relaxed);
+    let result = f();
+    STDOUT.store(previous, Ordering::Relaxed);
+    result
+}
+
+#[doc(hidden)]
+pub fn _stdout_print(args: fmt::Arguments) {
+    if STDOUT.load(Ordering::Relaxed) == Stdout::Serial as u8 {
+        crate::serial::_print(args);
+    } else {
+        crate::vga_buffer::_print(args);
+    }
+}
+
+/// Prints to the output of the running mal session, see `with_stdout`.
+#[macro_export]
+macro_rules! stdout_print {
+    ($($arg:tt)*) => ($crate::stdio::_stdout_print(format_args!($($arg)*)));
+}
+
+/// Prints to the output of the running mal session, appending a newline.
+#[macro_export]
+macro_rules! stdout_println {
+    () => ($crate::stdout_print!("\n"));
+    ($($arg:tt)*) => ($crate::stdout_print!("{}\n", format_args!($($arg)*)));
+}
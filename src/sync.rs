@@ -0,0 +1,136 @@
+//! A spin lock that knows which processor holds it.
+//!
+//! Exception handlers have to know whether the code they interrupted holds a
+//! kernel lock: returning to a recovery point would leak the lock, and taking
+//! it to print a report would deadlock. All kernel locks are therefore a
+//! `Mutex` from this module, which records the processor that holds it and
+//! counts the locks every processor holds. A guard must be dropped on the
+//! processor that took it.
+
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+
+use crate::smp;
+
+const NO_OWNER: usize = usize::max_value();
+
+/// How often `lock_for_output` tries a lock held by another processor while
+/// this one is panicking.
+const PANIC_SPINS: usize = 10_000_000;
+
+pub struct Mutex<T> {
+    inner: spin::Mutex<T>,
+    owner: AtomicUsize,
+}
+
+pub struct MutexGuard<'a, T> {
+    guard: spin::MutexGuard<'a, T>,
+    owner: &'a AtomicUsize,
+    cpu: usize,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            inner: spin::Mutex::new(value),
+            owner: AtomicUsize::new(NO_OWNER),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        let cpu = smp::current_index();
+        let guard = self.inner.lock();
+        self.acquired(guard, cpu)
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let cpu = smp::current_index();
+        let guard = self.inner.try_lock()?;
+        Some(self.acquired(guard, cpu))
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Returns whether the processor this runs on holds the lock.
+    pub fn is_held_here(&self) -> bool {
+        self.owner.load(Ordering::Acquire) == smp::current_index()
+    }
+
+    /// Locks the mutex from an exception handler. Returns `None` if the code
+    /// on this processor that the exception interrupted holds the lock, which
+    /// would never be released; a lock held by another processor is waited for.
+    pub fn lock_unless_held_here(&self) -> Option<MutexGuard<T>> {
+        if self.is_held_here() {
+            return None;
+        }
+        Some(self.lock())
+    }
+
+    /// Locks the mutex for printing, which also happens in exception handlers
+    /// and the panic handler and must not deadlock there.
+    ///
+    /// Returns `None` if code on this processor that an exception interrupted
+    /// holds the lock, unless this processor is panicking and will never return
+    /// to that code; then the lock is taken over. While panicking, a lock held
+    /// by another processor is only waited for a bounded time.
+    pub fn lock_for_output(&self) -> Option<MutexGuard<T>> {
+        let cpu = smp::current_index();
+        if !smp::cpu(cpu).is_panicking() {
+            return self.lock_unless_held_here();
+        }
+        for _ in 0..PANIC_SPINS {
+            if let Some(guard) = self.try_lock() {
+                return Some(guard);
+            }
+            if self.owner.load(Ordering::Acquire) == cpu {
+                // 拿着锁的代码再也不会运行, 没有别人在用
+                self.owner.store(NO_OWNER, Ordering::Release);
+                unsafe { self.inner.force_unlock() };
+                return self.try_lock();
+            }
+            spin_loop_hint();
+        }
+        None
+    }
+
+    fn acquired<'a>(&'a self, guard: spin::MutexGuard<'a, T>, cpu: usize) -> MutexGuard<'a, T> {
+        self.owner.store(cpu, Ordering::Release);
+        smp::cpu(cpu).locks_held.fetch_add(1, Ordering::Relaxed);
+        MutexGuard {
+            guard,
+            owner: &self.owner,
+            cpu,
+        }
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &*self.guard
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.guard
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // 在 guard 字段释放锁之前清掉持有者
+        self.owner.store(NO_OWNER, Ordering::Release);
+        smp::cpu(self.cpu)
+            .locks_held
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of `Mutex` locks the processor this runs on holds.
+pub fn locks_held() -> usize {
+    smp::current().locks_held.load(Ordering::Relaxed)
+}
@@ -2,34 +2,82 @@
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
 };
 use crossbeam_queue::ArrayQueue;
 use futures_util::stream::{Stream, StreamExt};
 use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
 
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1};
+use x86_64::instructions::port::Port;
 
+use super::layout::CurrentLayout;
 use crate::print;
 use crate::println;
+use crate::sync::Mutex;
 
 // 扫码队列
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
+// 丢掉的扫描码
+static DROPPED_QUEUE_FULL: AtomicU64 = AtomicU64::new(0);
+static DROPPED_UNINITIALIZED: AtomicU64 = AtomicU64::new(0);
 
-pub(crate) fn add_scancode(scancode: u8) {
+const IRQ: u8 = 1;
+const DATA_PORT: u16 = 0x60;
+
+/// Registers the keyboard interrupt handler.
+pub fn init() {
+    crate::interrupts::register_irq(IRQ, on_interrupt).expect("keyboard IRQ already taken");
+}
+
+fn on_interrupt() {
+    let mut port = Port::<u8>::new(DATA_PORT);
+    let scancode = unsafe { port.read() };
+    add_scancode(scancode);
+}
+
+fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
+            // 只在第一次提示, 之后看 irq-stats 里的计数
+            if DROPPED_QUEUE_FULL.fetch_add(1, Ordering::Relaxed) == 0 {
+                println!("WARNING: scancode queue full; dropping keyboard input");
+            }
         } else {
             //唤醒wake
             WAKER.wake();
         }
-    } else {
+    } else if DROPPED_UNINITIALIZED.fetch_add(1, Ordering::Relaxed) == 0 {
         println!("WARNING: scancode queue uninitialized");
     }
 }
 
+/// Scancodes the interrupt handler had to throw away.
+#[derive(Debug, Clone, Copy)]
+pub struct DroppedInput {
+    /// The queue was full because nobody read it fast enough.
+    pub queue_full: u64,
+    /// No `ScancodeStream` had been created yet.
+    pub uninitialized: u64,
+}
+
+/// Returns the number of dropped scancodes since boot.
+pub fn dropped_input() -> DroppedInput {
+    DroppedInput {
+        queue_full: DROPPED_QUEUE_FULL.load(Ordering::Relaxed),
+        uninitialized: DROPPED_UNINITIALIZED.load(Ordering::Relaxed),
+    }
+}
+
+/// Takes the next scancode without waiting, for code that runs outside the
+/// executor.
+pub(crate) fn pop_scancode() -> Option<u8> {
+    SCANCODE_QUEUE.try_get().ok()?.pop().ok()
+}
+
 pub struct ScancodeStream {
     //_private域的目的是防止从模块外部构造结构。这使new函数成为构造该类型的唯一方法。
     _private: (),
@@ -67,18 +115,103 @@ impl Stream for ScancodeStream {
     }
 }
 
+/// The modifier keys that are held down.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    /// The left Alt key.
+    pub alt: bool,
+    /// The right Alt key, which the European layouts use to type extra
+    /// characters, so it does not count as `alt`.
+    pub alt_gr: bool,
+}
+
+/// A key that was pressed or released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    /// What the key means in the current layout. Only set for presses of
+    /// keys other than the modifiers.
+    pub key: Option<DecodedKey>,
+    /// The modifiers held down after this event.
+    pub modifiers: Modifiers,
+    pub pressed: bool,
+}
+
+/// Turns scancodes into `KeyEvent`s and keeps track of the modifier keys
+/// across presses and releases.
+pub struct KeyDecoder {
+    keyboard: Keyboard<CurrentLayout, ScancodeSet1>,
+    modifiers: Modifiers,
+}
+
+impl KeyDecoder {
+    pub fn new() -> Self {
+        KeyDecoder {
+            keyboard: Keyboard::new(CurrentLayout, ScancodeSet1, HandleControl::Ignore),
+            modifiers: Modifiers::default(),
+        }
+    }
+
+    /// Returns the modifier keys that are held down.
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    /// Feeds one scancode and returns the event once a key press or release
+    /// is complete. Extended keys take more than one scancode.
+    pub fn add_scancode(&mut self, scancode: u8) -> Option<KeyEvent> {
+        let event = self.keyboard.add_byte(scancode).ok()??;
+        let code = event.code;
+        let pressed = event.state == KeyState::Down;
+        // pc_keyboard 自己也要看到所有事件, 才能处理 Shift 和大小写锁定
+        let key = self.keyboard.process_keyevent(event);
+        let held = match code {
+            KeyCode::ShiftLeft | KeyCode::ShiftRight => Some(&mut self.modifiers.shift),
+            KeyCode::ControlLeft | KeyCode::ControlRight => Some(&mut self.modifiers.ctrl),
+            KeyCode::AltLeft => Some(&mut self.modifiers.alt),
+            KeyCode::AltRight => Some(&mut self.modifiers.alt_gr),
+            _ => None,
+        };
+        // 修饰键只改变状态, 松开的键也没有字符
+        let key = match held {
+            Some(held) => {
+                *held = pressed;
+                None
+            }
+            None if pressed => key,
+            None => None,
+        };
+        Some(KeyEvent {
+            code,
+            key,
+            modifiers: self.modifiers,
+            pressed,
+        })
+    }
+}
+
+lazy_static! {
+    static ref DECODER: Mutex<KeyDecoder> = Mutex::new(KeyDecoder::new());
+}
+
+/// Feeds one scancode to the decoder shared by everything that reads the
+/// keyboard, so the modifier state stays right when the shell and a user
+/// program take turns reading keys.
+pub fn decode(scancode: u8) -> Option<KeyEvent> {
+    x86_64::instructions::interrupts::without_interrupts(|| DECODER.lock().add_scancode(scancode))
+}
+
 //打印键盘键入函数
 pub async fn print_keypresses() {
     let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
 
     while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
+        if let Some(KeyEvent { key: Some(key), .. }) = decode(scancode) {
+            match key {
+                DecodedKey::Unicode(character) => print!("{}", character),
+                DecodedKey::RawKey(key) => print!("{:?}", key),
             }
         }
     }
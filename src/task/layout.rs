@@ -0,0 +1,94 @@
+//! Keyboard layout configuration.
+//!
+//! Every decoder uses `CurrentLayout`, which looks up the layout selected with
+//! `set_layout` on each key press, so switching takes effect immediately
+//! everywhere.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyboardLayout, Modifiers};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    /// US 104 key
+    Us,
+    /// UK 105 key
+    Uk,
+    /// German 105 key
+    De,
+    /// French AZERTY
+    Fr,
+    /// Dvorak 104 key
+    Dvorak,
+}
+
+const LAYOUTS: [(&str, Layout); 5] = [
+    ("us", Layout::Us),
+    ("uk", Layout::Uk),
+    ("de", Layout::De),
+    ("fr", Layout::Fr),
+    ("dvorak", Layout::Dvorak),
+];
+
+impl Layout {
+    /// Looks up a layout by its short name, such as `de`.
+    pub fn from_name(name: &str) -> Option<Layout> {
+        LAYOUTS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, layout)| layout)
+    }
+
+    /// Returns the short name of the layout.
+    pub fn name(self) -> &'static str {
+        LAYOUTS
+            .iter()
+            .find(|(_, layout)| *layout == self)
+            .map(|&(name, _)| name)
+            .unwrap()
+    }
+
+    /// Returns the names of all layouts.
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        LAYOUTS.iter().map(|&(name, _)| name)
+    }
+
+    fn from_u8(value: u8) -> Layout {
+        LAYOUTS
+            .iter()
+            .find(|(_, layout)| *layout as u8 == value)
+            .map(|&(_, layout)| layout)
+            .unwrap_or(Layout::Us)
+    }
+}
+
+static CURRENT: AtomicU8 = AtomicU8::new(Layout::Us as u8);
+
+/// Returns the selected layout.
+pub fn layout() -> Layout {
+    Layout::from_u8(CURRENT.load(Ordering::Relaxed))
+}
+
+/// Selects the layout used to decode all following key presses.
+pub fn set_layout(layout: Layout) {
+    CURRENT.store(layout as u8, Ordering::Relaxed);
+}
+
+/// The layout selected with `set_layout`, for `pc_keyboard::Keyboard`.
+pub struct CurrentLayout;
+
+impl KeyboardLayout for CurrentLayout {
+    fn map_keycode(
+        keycode: KeyCode,
+        modifiers: &Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        match layout() {
+            Layout::Us => layouts::Us104Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Uk => layouts::Uk105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::De => layouts::De105Key::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Fr => layouts::Azerty::map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Dvorak => layouts::Dvorak104Key::map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
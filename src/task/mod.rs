@@ -8,7 +8,10 @@ use core::{
 
 pub mod executor;
 pub mod keyboard;
+pub mod layout;
+pub mod serial;
 pub mod simple_executor;
+pub mod timer;
 
 pub struct Task {
     id: TaskId,
@@ -0,0 +1,77 @@
+// OnceCell 保证一次性初始化
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+use crate::serial_println;
+
+// 串口收到的字节
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Enables the receive interrupt of COM1 and registers its handler.
+pub fn init() {
+    crate::serial::enable_receive_interrupt();
+    crate::interrupts::register_irq(crate::serial::COM1_IRQ, on_interrupt)
+        .expect("serial IRQ already taken");
+}
+
+fn on_interrupt() {
+    // 把 FIFO 里的字节都取出来
+    while let Some(byte) = crate::serial::receive() {
+        add_byte(byte);
+    }
+}
+
+fn add_byte(byte: u8) {
+    if let Ok(queue) = BYTE_QUEUE.try_get() {
+        if let Err(_) = queue.push(byte) {
+            serial_println!("WARNING: serial input queue full; dropping input");
+        } else {
+            WAKER.wake();
+        }
+    }
+    // 还没有人读串口的时候直接丢掉
+}
+
+/// The bytes received on COM1.
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    pub fn new() -> Self {
+        BYTE_QUEUE
+            .try_init_once(|| ArrayQueue::new(256))
+            .expect("SerialStream::new should only be called once");
+        SerialStream { _private: () }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = BYTE_QUEUE.try_get().expect("not initialized");
+
+        // fast path
+        if let Ok(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKER.register(&cx.waker());
+
+        match queue.pop() {
+            Ok(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
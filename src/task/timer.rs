@@ -0,0 +1,197 @@
+//! Timer-driven futures.
+//!
+//! Waiting futures take a slot in a fixed table holding their deadline in
+//! ticks and a waker. The timer interrupt wakes every slot whose deadline has
+//! passed, so sleeping tasks cost nothing until then.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+use crate::time;
+
+const MAX_TIMERS: usize = 64;
+/// Deadline of a free slot; real deadlines are stored as at least 1.
+const FREE: u64 = 0;
+
+struct TimerSlot {
+    deadline: AtomicU64,
+    waker: AtomicWaker,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const FREE_SLOT: TimerSlot = TimerSlot {
+    deadline: AtomicU64::new(FREE),
+    waker: AtomicWaker::new(),
+};
+
+// 定长的表, 时钟中断里不能动堆
+static TIMERS: [TimerSlot; MAX_TIMERS] = [FREE_SLOT; MAX_TIMERS];
+
+/// Called by the timer interrupt handler with the current tick count.
+pub(crate) fn wake_expired(now: u64) {
+    for slot in TIMERS.iter() {
+        let deadline = slot.deadline.load(Ordering::Acquire);
+        if deadline != FREE && deadline <= now {
+            slot.waker.wake();
+        }
+    }
+}
+
+fn claim_slot(deadline: u64) -> Option<usize> {
+    let deadline = deadline.max(1);
+    TIMERS.iter().position(|slot| {
+        slot.deadline
+            .compare_exchange(FREE, deadline, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    })
+}
+
+fn release_slot(index: usize) {
+    TIMERS[index].waker.take();
+    TIMERS[index].deadline.store(FREE, Ordering::Release);
+}
+
+/// A future that completes once a deadline has passed.
+pub struct Sleep {
+    deadline: u64,
+    slot: Option<usize>,
+}
+
+/// Waits for at least `ms` milliseconds.
+pub fn sleep(ms: u64) -> Sleep {
+    sleep_until(time::ticks() + time::ms_to_ticks(ms))
+}
+
+/// Waits until the tick counter reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep {
+        deadline,
+        slot: None,
+    }
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    fn reset(&mut self, deadline: u64) {
+        self.deadline = deadline;
+        if let Some(index) = self.slot {
+            TIMERS[index]
+                .deadline
+                .store(deadline.max(1), Ordering::Release);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        if time::ticks() >= this.deadline {
+            return Poll::Ready(());
+        }
+        if this.slot.is_none() {
+            this.slot = claim_slot(this.deadline);
+        }
+        match this.slot {
+            Some(index) => TIMERS[index].waker.register(cx.waker()),
+            // 表满了就退化成轮询
+            None => cx.waker().wake_by_ref(),
+        }
+        // 注册之前时钟中断可能已经来过了
+        if time::ticks() >= this.deadline {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(index) = self.slot.take() {
+            release_slot(index);
+        }
+    }
+}
+
+/// The error returned by `Timeout` when the deadline passed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// A future that runs another future until it completes or a deadline passes.
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// Runs `future` for at most `ms` milliseconds.
+pub fn timeout<F: Future>(ms: u64, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(ms),
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // future 字段从不移动, 所以可以按 pin 投影
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A stream that yields once every period, returned by `interval`.
+pub struct Interval {
+    period: u64,
+    sleep: Sleep,
+}
+
+/// Yields the tick count every `ms` milliseconds, starting `ms` from now.
+///
+/// Deadlines are spaced exactly one period apart, so a late consumer does not
+/// make the interval drift; ticks that were missed completely are skipped.
+pub fn interval(ms: u64) -> Interval {
+    let period = time::ms_to_ticks(ms).max(1);
+    Interval {
+        period,
+        sleep: sleep_until(time::ticks() + period),
+    }
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let now = time::ticks();
+                let mut next = this.sleep.deadline() + this.period;
+                if next <= now {
+                    next += (now - next) / this.period * this.period + this.period;
+                }
+                this.sleep.reset(next);
+                Poll::Ready(Some(now))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Kernel time keeping.
+//!
+//! The PIT raises the timer interrupt `TICK_HZ` times per second and every
+//! interrupt advances a monotonic tick counter, which is the kernel's clock.
+//! `tsc` adds a nanosecond clock on top of it where the CPU allows, and `rtc`
+//! the calendar time.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub mod pit;
+pub mod rtc;
+pub mod tsc;
+
+/// The nominal frequency of the timer interrupt.
+pub const TICK_HZ: u64 = 1000;
+
+const DIVISOR: u16 = pit::divisor(TICK_HZ);
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the RTC, calibrates the TSC and programs the timer. Must be called
+/// before interrupts are enabled.
+pub fn init() {
+    rtc::init();
+    tsc::init();
+    pit::init(DIVISOR);
+    crate::interrupts::register_irq(pit::IRQ, on_tick).expect("timer IRQ already taken");
+}
+
+/// Handler of the PIT interrupt.
+fn on_tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    // 唤醒到期的定时器
+    crate::task::timer::wake_expired(now);
+}
+
+/// Returns the number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Converts a tick count into nanoseconds, using the real PIT period rather
+/// than the nominal `TICK_HZ`.
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    (u128::from(ticks) * u128::from(DIVISOR) * 1_000_000_000 / u128::from(pit::BASE_FREQUENCY))
+        as u64
+}
+
+/// Returns the number of ticks that last at least `ms` milliseconds.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let period = u128::from(DIVISOR) * 1000;
+    ((u128::from(ms) * u128::from(pit::BASE_FREQUENCY) + period - 1) / period) as u64
+}
+
+/// Returns the time since boot in milliseconds.
+pub fn uptime_ms() -> u64 {
+    ticks_to_ns(ticks()) / 1_000_000
+}
+
+/// Returns the time since boot in nanoseconds, from the TSC if possible.
+pub fn now_ns() -> u64 {
+    tsc::now_ns()
+}
+
+/// Spins for at least `us` microseconds. Needs a working TSC or interrupts
+/// enabled.
+pub fn busy_wait_us(us: u64) {
+    let end = now_ns() + us * 1000;
+    while now_ns() < end {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
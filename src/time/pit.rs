@@ -0,0 +1,58 @@
+//! The 8253/8254 programmable interval timer.
+
+use x86_64::instructions::port::Port;
+
+/// The IRQ line of channel 0.
+pub const IRQ: u8 = 0;
+
+/// The input clock of the PIT in Hz.
+pub const BASE_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Gate and output of channel 2, shared with the PC speaker.
+const SPEAKER_CONTROL: u16 = 0x61;
+
+/// Returns the reload value that makes channel 0 fire `hz` times per second.
+pub const fn divisor(hz: u64) -> u16 {
+    (BASE_FREQUENCY / hz) as u16
+}
+
+/// Programs channel 0 to raise IRQ 0 with the given reload value.
+pub fn init(divisor: u16) {
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL_0);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        // 通道 0, 先写低字节再写高字节, 方波模式
+        command.write(0b0011_0110);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    });
+}
+
+/// Counts down `count` input clocks on channel 2 and calls `before` right
+/// after the count started and `after` right after it ran out, busy-waiting in
+/// between. Channel 2 is not wired to an interrupt, so this works with
+/// interrupts disabled.
+pub fn time_one_shot<T>(
+    count: u16,
+    before: impl FnOnce() -> T,
+    after: impl FnOnce() -> T,
+) -> (T, T) {
+    let mut control = Port::<u8>::new(SPEAKER_CONTROL);
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL_2);
+    unsafe {
+        // 打开通道 2 的门控, 关掉扬声器
+        let value = control.read();
+        control.write((value & !0x02) | 0x01);
+        // 通道 2, 先写低字节再写高字节, 计数结束时输出变高
+        command.write(0b1011_0000);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+        let start = before();
+        while control.read() & 0x20 == 0 {}
+        (start, after())
+    }
+}
@@ -0,0 +1,202 @@
+//! CMOS real-time clock and calendar arithmetic.
+//!
+//! The RTC is read once at boot. Afterwards the wall-clock time is the boot
+//! time plus the uptime, which is cheaper than reading the CMOS every time and
+//! never goes backwards.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECOND: u8 = 0x00;
+const REG_MINUTE: u8 = 0x02;
+const REG_HOUR: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+/// Not standardized, but present on most PCs and in QEMU.
+const REG_CENTURY: u8 = 0x32;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const HOUR_PM: u8 = 0x80;
+
+/// Unix time of boot in seconds, zero until `init` ran.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// A calendar date and time of day in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Converts seconds since 1970-01-01 00:00:00 UTC into a date and time.
+    pub fn from_unix(seconds: u64) -> Self {
+        let days = (seconds / 86400) as i64;
+        let time = seconds % 86400;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year as u16,
+            month,
+            day,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    /// Returns the seconds since 1970-01-01 00:00:00 UTC.
+    pub fn to_unix(&self) -> u64 {
+        let days = days_from_civil(i64::from(self.year), self.month, self.day);
+        days as u64 * 86400
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+
+    /// Returns the day of the week, 0 for Sunday.
+    pub fn weekday(&self) -> u8 {
+        // 1970-01-01 是星期四
+        ((days_from_civil(i64::from(self.year), self.month, self.day) + 4).rem_euclid(7)) as u8
+    }
+}
+
+/// Formats as `YYYY-MM-DD HH:MM:SS`.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Reads the RTC and remembers the boot time. Must be called with interrupts
+/// disabled and before the tick counter advances much.
+pub fn init() {
+    let now = read_rtc().to_unix();
+    let uptime = super::uptime_ms() / 1000;
+    BOOT_TIME.store(now.saturating_sub(uptime).max(1), Ordering::Relaxed);
+}
+
+/// Returns the current Unix time in seconds, if the RTC was read.
+pub fn unix_time() -> Option<u64> {
+    match BOOT_TIME.load(Ordering::Relaxed) {
+        0 => None,
+        boot => Some(boot + super::uptime_ms() / 1000),
+    }
+}
+
+/// Returns the current date and time, if the RTC was read.
+pub fn now() -> Option<DateTime> {
+    unix_time().map(DateTime::from_unix)
+}
+
+/// Reads the date and time directly from the CMOS.
+pub fn read_rtc() -> DateTime {
+    // 两次读到的值一样才算数, 避免读到一半时钟更新了
+    let mut last = read_registers();
+    loop {
+        let current = read_registers();
+        if current == last {
+            break;
+        }
+        last = current;
+    }
+    let [second, minute, hour, day, month, year, century] = last;
+
+    let status_b = read_cmos(REG_STATUS_B);
+    let decode = |value: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            (value & 0x0f) + (value >> 4) * 10
+        }
+    };
+    // 12 小时制时最高位表示下午, 12 点记作 12
+    let pm = hour & HOUR_PM != 0;
+    let mut hour = decode(hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    let century = match decode(century) {
+        century @ 19..=30 => u16::from(century),
+        _ => 20,
+    };
+
+    DateTime {
+        year: century * 100 + u16::from(decode(year)),
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+fn read_registers() -> [u8; 7] {
+    while read_cmos(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {}
+    [
+        read_cmos(REG_SECOND),
+        read_cmos(REG_MINUTE),
+        read_cmos(REG_HOUR),
+        read_cmos(REG_DAY),
+        read_cmos(REG_MONTH),
+        read_cmos(REG_YEAR),
+        read_cmos(REG_CENTURY),
+    ]
+}
+
+fn read_cmos(register: u8) -> u8 {
+    let mut address = Port::<u8>::new(CMOS_ADDRESS);
+    let mut data = Port::<u8>::new(CMOS_DATA);
+    unsafe {
+        address.write(register);
+        data.read()
+    }
+}
+
+// 下面两个函数来自 Howard Hinnant 的日期算法, 以 3 月作为一年的开始
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (i64::from(month) + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
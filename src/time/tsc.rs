@@ -0,0 +1,68 @@
+//! High resolution clock based on the time stamp counter.
+//!
+//! The TSC frequency is measured once at boot against the PIT. The TSC is only
+//! used as a clock if it is invariant, i.e. it runs at a constant rate in all
+//! power states; otherwise `now_ns` falls back to the tick counter.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use log::*;
+
+use super::pit;
+
+/// Length of the calibration run in PIT input clocks, about 10 ms.
+const CALIBRATION_COUNT: u16 = 11_932;
+
+/// Measured TSC frequency in Hz, zero if the TSC is not used.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// TSC value and uptime in nanoseconds when the TSC was calibrated.
+static BASE_TSC: AtomicU64 = AtomicU64::new(0);
+static BASE_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns whether the CPU reports an invariant TSC.
+pub fn is_invariant() -> bool {
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended < 0x8000_0007 {
+        return false;
+    }
+    unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// Reads the time stamp counter.
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Measures the TSC frequency. Must be called with interrupts disabled.
+pub fn init() {
+    if !is_invariant() {
+        warn!("TSC is not invariant, falling back to the timer ticks");
+        return;
+    }
+    let (start, end) = pit::time_one_shot(CALIBRATION_COUNT, read, read);
+    let frequency = (end - start) * pit::BASE_FREQUENCY / u64::from(CALIBRATION_COUNT);
+    BASE_TSC.store(end, Ordering::Relaxed);
+    BASE_NS.store(super::ticks_to_ns(super::ticks()), Ordering::Relaxed);
+    FREQUENCY.store(frequency, Ordering::Release);
+    info!("TSC runs at {} kHz", frequency / 1000);
+}
+
+/// Returns the measured TSC frequency in Hz, if the TSC is used as a clock.
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::Acquire) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Returns the time since boot in nanoseconds.
+pub fn now_ns() -> u64 {
+    match frequency() {
+        Some(frequency) => {
+            let elapsed = read().wrapping_sub(BASE_TSC.load(Ordering::Relaxed));
+            let ns = u128::from(elapsed) * 1_000_000_000 / u128::from(frequency);
+            BASE_NS.load(Ordering::Relaxed) + ns as u64
+        }
+        None => super::ticks_to_ns(super::ticks()),
+    }
+}
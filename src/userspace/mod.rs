@@ -0,0 +1,219 @@
+//! Running code in ring 3.
+//!
+//! A user program gets an address space of its own with a code page and a
+//! stack page in the first level 4 entry that does not belong to the kernel.
+//! `run` enters it with `iretq` and returns when the program calls `exit` or
+//! causes an exception; the program can only reach the kernel through the
+//! system calls in `syscall`.
+
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+use crate::memory::address_space::{self, AddressSpace, AddressSpaceError};
+use crate::memory::phys_to_virt;
+use crate::sync::Mutex;
+
+pub mod syscall;
+
+const PAGE_SIZE: u64 = 4096;
+
+global_asm!(
+    r#"
+    .global user_enter
+user_enter:
+    push %rbx
+    push %rbp
+    push %r12
+    push %r13
+    push %r14
+    push %r15
+    mov %rsp, USER_RETURN_RSP(%rip)
+    mov %rsp, SYSCALL_KERNEL_RSP(%rip)
+    push %rcx
+    push %rsi
+    push $0x202
+    push %rdx
+    push %rdi
+    xor %eax, %eax
+    xor %ebx, %ebx
+    xor %ecx, %ecx
+    xor %edx, %edx
+    xor %esi, %esi
+    xor %edi, %edi
+    xor %ebp, %ebp
+    xor %r8d, %r8d
+    xor %r9d, %r9d
+    xor %r10d, %r10d
+    xor %r11d, %r11d
+    xor %r12d, %r12d
+    xor %r13d, %r13d
+    xor %r14d, %r14d
+    xor %r15d, %r15d
+    iretq
+
+    .global user_return
+user_return:
+    mov %rdi, %rax
+    mov USER_RETURN_RSP(%rip), %rsp
+    pop %r15
+    pop %r14
+    pop %r13
+    pop %r12
+    pop %rbp
+    pop %rbx
+    ret
+
+    .global user_demo_start
+    .global user_demo_end
+user_demo_start:
+    lea user_demo_message(%rip), %rdi
+    mov $(user_demo_message_end - user_demo_message), %esi
+    xor %eax, %eax
+    syscall
+    mov $3, %eax
+    syscall
+    mov $1, %eax
+    syscall
+    push %rax
+    mov %rsp, %rdi
+    mov $1, %esi
+    xor %eax, %eax
+    syscall
+    pop %rdi
+    mov $2, %eax
+    syscall
+    ud2
+user_demo_message:
+    .ascii "hello from ring 3, press a key: "
+user_demo_message_end:
+user_demo_end:
+"#
+);
+
+extern "C" {
+    /// Saves the callee-saved registers and enters ring 3 at `entry` with the
+    /// stack `stack`. Returns the value passed to `user_return`.
+    fn user_enter(entry: u64, stack: u64, code_selector: u64, data_selector: u64) -> u64;
+    /// Returns from `user_enter` with `value`. Must run on a kernel stack
+    /// below the frame of `user_enter`.
+    fn user_return(value: u64) -> !;
+    static user_demo_start: u8;
+    static user_demo_end: u8;
+}
+
+// 进入用户态之前内核的栈指针, 也是系统调用使用的栈
+#[no_mangle]
+static mut USER_RETURN_RSP: u64 = 0;
+#[no_mangle]
+static mut SYSCALL_KERNEL_RSP: u64 = 0;
+
+static USER_FAULT: Mutex<Option<&'static str>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserError {
+    /// The address space could not be set up.
+    AddressSpace(AddressSpaceError),
+    /// The program is larger than a page.
+    TooLarge,
+    /// The program was killed by the named exception.
+    Fault(&'static str),
+}
+
+/// Runs `code` in ring 3 and returns its exit code.
+///
+/// The code is copied to the start of a fresh address space and must be
+/// position independent. Only one user program runs at a time, on the
+/// bootstrap processor.
+pub fn run(code: &[u8]) -> Result<u64, UserError> {
+    if code.len() as u64 > PAGE_SIZE {
+        return Err(UserError::TooLarge);
+    }
+    let mut space = AddressSpace::new().map_err(UserError::AddressSpace)?;
+    let base = user_base();
+    let code_page = Page::containing_address(base);
+    let stack_page = Page::containing_address(base + PAGE_SIZE * 2);
+
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let frame = space
+        .map_new(code_page, user)
+        .map_err(UserError::AddressSpace)?;
+    space
+        .map_new(
+            stack_page,
+            user | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .map_err(UserError::AddressSpace)?;
+    let target = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    unsafe { core::ptr::copy_nonoverlapping(code.as_ptr(), target, code.len()) };
+
+    let selectors = crate::gdt::selectors();
+    let were_enabled = x86_64::instructions::interrupts::are_enabled();
+    *USER_FAULT.lock() = None;
+    let exit_code = unsafe {
+        space.switch();
+        user_enter(
+            code_page.start_address().as_u64(),
+            stack_page.start_address().as_u64() + PAGE_SIZE,
+            u64::from(selectors.user_code_selector.0),
+            u64::from(selectors.user_data_selector.0),
+        )
+    };
+    // exit 是在关中断的系统调用里返回的
+    if were_enabled {
+        x86_64::instructions::interrupts::enable();
+    }
+    address_space::switch_to_kernel();
+    drop(space);
+
+    match USER_FAULT.lock().take() {
+        Some(name) => Err(UserError::Fault(name)),
+        None => Ok(exit_code),
+    }
+}
+
+/// Runs the built-in demo program, which greets, echoes one key and exits
+/// with its code.
+pub fn run_demo() -> Result<u64, UserError> {
+    let code = unsafe {
+        let start = &user_demo_start as *const u8;
+        let len = &user_demo_end as *const u8 as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+    run(code)
+}
+
+/// Returns the start of the first level 4 entry that is not shared with the kernel.
+fn user_base() -> VirtAddr {
+    let index = (1..256)
+        .find(|&index| !address_space::is_kernel_entry(index))
+        .expect("no level 4 entry left for user space");
+    VirtAddr::new((index as u64) << 39)
+}
+
+/// Ends the running user program if the exception in `stack_frame` came from
+/// ring 3, by making the handler return into `user_return` instead.
+///
+/// Returns `false` for exceptions in the kernel.
+pub fn abort_from_exception(stack_frame: &mut InterruptStackFrame, name: &'static str) -> bool {
+    if stack_frame.code_segment & 3 != 3 {
+        return false;
+    }
+    if let Some(mut fault) = USER_FAULT.try_lock() {
+        *fault = Some(name);
+    }
+    let selectors = crate::gdt::selectors();
+    unsafe {
+        let frame = stack_frame.as_mut();
+        frame.instruction_pointer = VirtAddr::new(abort_user as usize as u64);
+        frame.code_segment = u64::from(selectors.code_selector.0);
+        frame.stack_segment = u64::from(selectors.data_selector.0);
+        // user_return 会换回原来的栈, 这里只要是一个能用的内核栈
+        frame.stack_pointer = VirtAddr::new(SYSCALL_KERNEL_RSP - 8);
+    }
+    true
+}
+
+extern "C" fn abort_user() -> ! {
+    unsafe { user_return(u64::max_value()) }
+}
@@ -0,0 +1,153 @@
+//! The `syscall` instruction interface.
+//!
+//! The system call number is passed in `rax` and up to three arguments in
+//! `rdi`, `rsi` and `rdx`; the result comes back in `rax`. Like a function
+//! call in the System V ABI, a system call may clobber all caller-saved
+//! registers. Interrupts are off while the kernel handles a system call unless
+//! the call itself waits for one.
+
+use alloc::string::String;
+use pc_keyboard::DecodedKey;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
+
+use crate::memory::address_space;
+use crate::stdout_print;
+use crate::task::keyboard::{self, KeyEvent};
+
+/// Writes `rsi` bytes at `rdi` to the console and returns the number written.
+pub const SYS_WRITE: u64 = 0;
+/// Waits for a key press and returns its character.
+pub const SYS_READ_KEY: u64 = 1;
+/// Ends the program with the exit code in `rdi`.
+pub const SYS_EXIT: u64 = 2;
+/// Lets pending interrupts run before the program continues.
+pub const SYS_YIELD: u64 = 3;
+
+/// Returned for unknown system calls and invalid arguments.
+pub const ERROR: u64 = u64::max_value();
+
+const IA32_EFER: u32 = 0xc000_0080;
+const IA32_STAR: u32 = 0xc000_0081;
+const IA32_LSTAR: u32 = 0xc000_0082;
+const IA32_FMASK: u32 = 0xc000_0084;
+const EFER_SYSTEM_CALL_EXTENSIONS: u64 = 1;
+// 进入内核时清掉中断, 方向和单步标志
+const FMASK_FLAGS: u64 = 0x200 | 0x400 | 0x100;
+
+type Handler = fn(u64, u64, u64) -> u64;
+
+static SYSCALLS: [Handler; 4] = [sys_write, sys_read_key, sys_exit, sys_yield];
+
+// SYSCALL_KERNEL_RSP 是 user_enter 压了返回地址和 6 个寄存器之后的栈指针,
+// 模 16 余 8; 再压 3 个寄存器后 call 之前正好 16 字节对齐, 不需要再调整
+global_asm!(
+    r#"
+    .global syscall_entry
+syscall_entry:
+    mov %rsp, SYSCALL_USER_RSP(%rip)
+    mov SYSCALL_KERNEL_RSP(%rip), %rsp
+    push SYSCALL_USER_RSP(%rip)
+    push %rcx
+    push %r11
+    mov %rdx, %rcx
+    mov %rsi, %rdx
+    mov %rdi, %rsi
+    mov %rax, %rdi
+    call syscall_dispatch
+    pop %r11
+    pop %rcx
+    pop %rsp
+    sysretq
+"#
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+#[no_mangle]
+static mut SYSCALL_USER_RSP: u64 = 0;
+
+/// Enables the `syscall` instruction on the current processor.
+pub fn init() {
+    let selectors = crate::gdt::selectors();
+    // sysret 从 STAR[63:48] + 8 取栈段, + 16 取代码段
+    let user_base = u64::from(selectors.user_data_selector.0 & !3) - 8;
+    let kernel_base = u64::from(selectors.code_selector.0);
+    unsafe {
+        let mut efer = Msr::new(IA32_EFER);
+        let value = efer.read();
+        efer.write(value | EFER_SYSTEM_CALL_EXTENSIONS);
+        Msr::new(IA32_STAR).write(user_base << 48 | kernel_base << 32);
+        Msr::new(IA32_LSTAR).write(syscall_entry as usize as u64);
+        Msr::new(IA32_FMASK).write(FMASK_FLAGS);
+    }
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(number: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    match SYSCALLS.get(number as usize) {
+        Some(handler) => handler(arg0, arg1, arg2),
+        None => ERROR,
+    }
+}
+
+/// Returns whether `len` bytes at `addr` are mapped in the user part of the
+/// current address space.
+fn is_user_range(addr: u64, len: u64) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) if end <= 0x0000_8000_0000_0000 => end,
+        _ => return false,
+    };
+    let mut page_addr = addr & !0xfff;
+    while page_addr < end {
+        let page = Page::containing_address(VirtAddr::new(page_addr));
+        if address_space::is_kernel_entry(usize::from(page.p4_index()))
+            || !address_space::is_user_mapped(page)
+        {
+            return false;
+        }
+        page_addr += 4096;
+    }
+    true
+}
+
+fn sys_write(addr: u64, len: u64, _: u64) -> u64 {
+    if !is_user_range(addr, len) {
+        return ERROR;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) };
+    stdout_print!("{}", String::from_utf8_lossy(bytes));
+    len
+}
+
+fn sys_read_key(_: u64, _: u64, _: u64) -> u64 {
+    use x86_64::instructions::interrupts;
+
+    loop {
+        while let Some(scancode) = keyboard::pop_scancode() {
+            if let Some(KeyEvent {
+                key: Some(DecodedKey::Unicode(c)),
+                ..
+            }) = keyboard::decode(scancode)
+            {
+                return u64::from(u32::from(c));
+            }
+        }
+        // 等键盘中断把扫描码放进队列
+        interrupts::enable_and_hlt();
+        interrupts::disable();
+    }
+}
+
+fn sys_exit(code: u64, _: u64, _: u64) -> u64 {
+    unsafe { super::user_return(code) }
+}
+
+fn sys_yield(_: u64, _: u64, _: u64) -> u64 {
+    x86_64::instructions::interrupts::enable_and_hlt();
+    x86_64::instructions::interrupts::disable();
+    0
+}
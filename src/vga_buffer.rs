@@ -121,6 +121,15 @@ impl Writer {
         self.column_position = 0;
     }
 
+    // 清屏, 光标回到最后一行开头
+    pub fn clear_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+        unsafe { update_cursor(BUFFER_HEIGHT - 1, 0) }
+    }
+
     fn clear_row(&mut self, row: usize) {
         let blank = ScreenChar {
             ascii_character: b' ',
@@ -150,8 +159,8 @@ impl fmt::Write for Writer {
     }
 }
 
+use crate::sync::Mutex;
 use lazy_static::lazy_static;
-use spin::Mutex;
 
 lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
@@ -179,13 +188,23 @@ macro_rules! del {
     };
 }
 
+#[macro_export]
+macro_rules! clear_screen {
+    () => {
+        $crate::vga_buffer::_clear_screen()
+    };
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
+    // 异常和 panic 时也从这里打印, 拿不到锁就不打印
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        if let Some(mut writer) = WRITER.lock_for_output() {
+            writer.write_fmt(args).unwrap();
+        }
     });
 }
 
@@ -198,6 +217,14 @@ pub fn _del() {
     })
 }
 
+#[doc(hidden)]
+pub fn _clear_screen() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        WRITER.lock().clear_screen();
+    })
+}
+
 /**
  * 移动光标
  */